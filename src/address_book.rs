@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+// Stable identity of a node, it stays the same when the node moves to
+// another address
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone, Default, Deserialize, Serialize, Debug)]
+pub struct NodeId(pub u64);
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u64> for NodeId {
+    fn from(id: u64) -> NodeId {
+        NodeId(id)
+    }
+}
+
+// Where each node can be reached. Shared by the Raft loop, the RPC layer and
+// the NodeHandle, updating an address does not change the membership.
+#[derive(Clone, Default)]
+pub struct AddressBook {
+    addrs: Arc<RwLock<HashMap<NodeId, SocketAddr>>>,
+}
+
+impl AddressBook {
+    pub fn new(addrs: impl IntoIterator<Item = (NodeId, SocketAddr)>) -> AddressBook {
+        AddressBook {
+            addrs: Arc::new(RwLock::new(addrs.into_iter().collect())),
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<SocketAddr> {
+        self.addrs.read().unwrap().get(&id).copied()
+    }
+
+    // Record the address of `id`, replacing the previous one
+    pub fn set(&self, id: NodeId, addr: SocketAddr) {
+        if self.get(id) != Some(addr) {
+            self.addrs.write().unwrap().insert(id, addr);
+        }
+    }
+}
@@ -2,37 +2,67 @@ extern crate simple_logger;
 extern crate clap;
 
 use log::*;
-use clap::{App, Arg};
-use ruft::Node;
+use ruft::{Config, Entry, FileStorage, Node, NodeId, StateMachine};
+use std::collections::HashMap;
+use std::error::Error;
 use std::thread;
 use std::time::Duration;
 
+// Key-value store driven by UTF-8 "key=value" commands
+#[derive(Default)]
+struct KvStore {
+    data: HashMap<String, String>,
+}
+
+impl StateMachine for KvStore {
+    type Output = Option<String>;
+
+    fn apply(&mut self, entry: &Entry) -> Option<String> {
+        let command = String::from_utf8_lossy(&entry.command);
+        let mut parts = command.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => self.data.insert(key.to_string(), value.to_string()),
+            _ => None,
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.data).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.data = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+}
+
 macro_rules! start_node {
-    ($id: expr) => {
-        let mut peers = vec![
-            String::from("127.0.0.1:8000"),
-            String::from("127.0.0.1:8001"),
-            String::from("127.0.0.1:8002"),
-            String::from("127.0.0.1:8003"),
-            String::from("127.0.0.1:8004"),
-        ];
-        peers.remove($id);
+    ($id: expr) => {{
+        let peers = (0..5)
+            .filter(|peer| *peer != $id)
+            .map(|peer| (NodeId(peer), format!("127.0.0.1:{}", 8000 + peer)))
+            .collect();
         let node = Node::new(
+            NodeId($id),
             String::from("127.0.0.1"), 
             8000 + ($id as u16),
-            5,
-            50,   // heartbeat
-            peers
+            peers,
+            FileStorage::open(format!("data/node-{}", $id)).unwrap(),
+            KvStore::default(),
+            Config {
+                heartbeat_interval: 50,
+                ..Config::default()
+            }
         );
-        let mut node = match node {
+        let node = match node {
             Ok(node) => node,
             Err(error) => panic!("Creating Node Error: {}", error),
         };
-        match node.run() {
-            Ok(()) => info!("{} stopped", node.rpc.cs.socket_addr.port()),
+        match node.start() {
+            Ok(handle) => handle,
             Err(error) => panic!("Running Node Error: {}", error),
-        };
-    };
+        }
+    }};
 }
 
 fn main() {
@@ -110,10 +140,22 @@ fn main() {
     //     Err(error) => error!("Running Node Error: {}", error),
     // };
 
-    for id in 0..5 {
-        thread::spawn(move || {
-            start_node!(id);
-        });
+    let handles: Vec<_> = (0..5).map(|id| start_node!(id)).collect();
+    thread::sleep(Duration::from_secs(1));
+    for (i, handle) in handles.iter().enumerate() {
+        if let Ok(proposal) = handle.propose(format!("key{}=value{}", i, i)) {
+            info!("Proposed at index {} in term {}", proposal.index, proposal.term);
+            match proposal.wait() {
+                Ok(previous) => info!("Applied, previous value: {:?}", previous),
+                Err(error) => error!("Proposal failed: {}", error),
+            }
+        }
+    }
+    thread::sleep(Duration::from_secs(2));
+    for handle in handles {
+        if let Some(status) = handle.status() {
+            info!("{:?}", status);
+        }
+        handle.shutdown();
     }
-    thread::sleep(Duration::from_secs(3));
 }
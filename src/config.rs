@@ -0,0 +1,59 @@
+use crate::rpc::Codec;
+
+// Tunables of a Node that are not part of the cluster layout
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Milliseconds between two heartbeats of a leader
+    pub heartbeat_interval: u32,
+    // Check that an election can be won before starting it, so that a node
+    // rejoining after a partition does not disrupt the leader with its term
+    pub pre_vote: bool,
+    // Step down as leader after an election timeout without responses from
+    // a majority, so that an isolated leader does not linger
+    pub check_quorum: bool,
+    // Milliseconds a leadership transfer may take before it is abandoned
+    pub transfer_timeout: u32,
+    // Take a snapshot once the log holds this many entries
+    pub snapshot_max_entries: usize,
+    // ... or once the entries in the log add up to this many bytes
+    pub snapshot_max_bytes: usize,
+    // Most bytes the snapshot data of one InstallSnapshotRequest takes once
+    // encoded with `codec`. The default leaves room for the rest of the
+    // request in a 65 507 bytes UDP datagram. TcpTransport only limits whole
+    // frames to 64 MiB, larger chunks mean fewer round trips.
+    pub snapshot_chunk_size: usize,
+    // Most entries sent to a follower in one AppendEntriesRequest, and most
+    // proposals appended to the leader's log in one write
+    pub max_append_entries: usize,
+    // ... and most bytes of encoded entries in them, leaving room for the
+    // rest of the request in a UDP datagram by default. A larger entry is
+    // sent on its own, proposals that do not fit in a message of the
+    // transport are rejected.
+    pub max_append_bytes: usize,
+    // Most AppendEntriesRequests sent to a follower without a response
+    pub max_inflight_msgs: usize,
+    // ... and most bytes of entries in them
+    pub max_inflight_bytes: usize,
+    // Encoding of the messages sent by the UdpTransport of Node::new, also
+    // used to size entries against max_append_bytes and snapshot chunks
+    pub codec: Codec,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            heartbeat_interval: 50,
+            pre_vote: false,
+            check_quorum: false,
+            transfer_timeout: 1000,
+            snapshot_max_entries: 10_000,
+            snapshot_max_bytes: 64 * 1024 * 1024,
+            snapshot_chunk_size: 60 * 1024,
+            max_append_entries: 8,
+            max_append_bytes: 60 * 1024,
+            max_inflight_msgs: 32,
+            max_inflight_bytes: 1024 * 1024,
+            codec: Codec::default(),
+        }
+    }
+}
@@ -1,8 +1,27 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Deserialize, Serialize, Debug)]
+// What an entry holds. Only Normal entries reach the state machine, the
+// others are records of the protocol itself.
+#[derive(PartialEq, Copy, Clone, Deserialize, Serialize, Debug)]
+pub enum EntryKind {
+    Normal,
+    // appended by a new leader to commit the entries of earlier terms
+    NoOp,
+    // a change of the cluster membership, the command is the Membership
+    // encoded like a stored record
+    ConfigChange,
+}
+
+#[derive(PartialEq, Clone, Deserialize, Serialize, Debug)]
 pub struct Entry {
     pub index: usize,
     pub term: u32,
-    pub command: String,
-}
\ No newline at end of file
+    pub kind: EntryKind,
+    pub command: Vec<u8>, // opaque to the node, encoded by the application
+}
+impl Entry {
+    // Approximate number of bytes the entry takes in the log
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<usize>() + std::mem::size_of::<u32>() + self.command.len()
+    }
+}
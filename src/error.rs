@@ -1,5 +1,8 @@
+use crate::address_book::NodeId;
+
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum InitializationError {
@@ -17,3 +20,133 @@ impl fmt::Display for InitializationError {
 }
 
 impl Error for InitializationError {}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Corrupted(PathBuf),
+    NonContiguous { expected: usize, found: usize },
+    Compacted(usize),
+    Unavailable(usize),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Corrupted(path) => write!(f, "Corrupted log segment {}", path.display()),
+            StorageError::NonContiguous { expected, found } => write!(
+                f,
+                "Non-contiguous log append, expected index {} but found {}",
+                expected, found
+            ),
+            StorageError::Compacted(index) => {
+                write!(f, "Log entry {} has been compacted into a snapshot", index)
+            }
+            StorageError::Unavailable(index) => write!(f, "Log entry {} is unavailable", index),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Empty,
+    UnknownFormat(u8),
+    TooLarge(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Empty => write!(f, "Empty message"),
+            CodecError::UnknownFormat(format) => write!(f, "Unknown message format {}", format),
+            CodecError::TooLarge(size) => write!(f, "Message of {} bytes is too large", size),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+#[derive(PartialEq, Debug)]
+pub enum ProposeError {
+    // This node is not the leader, `leader_hint` is the last leader it heard of
+    NotLeader { leader_hint: Option<NodeId> },
+    // The entry was overwritten by another leader before being committed
+    Dropped,
+    // The leader could not persist the entry
+    StorageFailure,
+    // The leader is handing leadership over to another node
+    TransferringLeadership,
+    // Another membership change has not completed yet
+    MembershipChangeInProgress,
+    // Only learners can be promoted
+    NotLearner(NodeId),
+    // The learner is missing committed entries and cannot be promoted yet
+    LearnerBehind(NodeId),
+    // The encoded entry, of this many bytes, does not fit in a message of
+    // the transport
+    TooLarge(usize),
+    // The entry was replaced by a snapshot from the leader before being
+    // applied, it may or may not have been committed
+    OutcomeUnknown,
+    // The node is not running anymore
+    Stopped,
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader {
+                leader_hint: Some(leader),
+            } => write!(f, "Not the leader, try node {}", leader),
+            ProposeError::NotLeader { leader_hint: None } => write!(f, "Not the leader"),
+            ProposeError::Dropped => write!(f, "Entry dropped by a new leader"),
+            ProposeError::StorageFailure => write!(f, "Failed to persist entry"),
+            ProposeError::TransferringLeadership => write!(f, "Leadership transfer in progress"),
+            ProposeError::MembershipChangeInProgress => {
+                write!(f, "Membership change in progress")
+            }
+            ProposeError::NotLearner(id) => write!(f, "Node {} is not a learner", id),
+            ProposeError::LearnerBehind(id) => write!(f, "Node {} has not caught up yet", id),
+            ProposeError::TooLarge(size) => {
+                write!(f, "Entry of {} bytes does not fit in a message", size)
+            }
+            ProposeError::OutcomeUnknown => write!(f, "Entry replaced by a snapshot, outcome unknown"),
+            ProposeError::Stopped => write!(f, "Node stopped"),
+        }
+    }
+}
+
+impl Error for ProposeError {}
+
+#[derive(PartialEq, Debug)]
+pub enum TransferError {
+    // Only the leader can transfer leadership
+    NotLeader { leader_hint: Option<NodeId> },
+    // The target is not a member of the cluster
+    UnknownTarget(NodeId),
+    // The target did not catch up and take over in time
+    TimedOut,
+    // Cancelled, replaced by another transfer, or the leader stepped down
+    // before the target was told to take over
+    Aborted,
+    // The node is not running anymore
+    Stopped,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::NotLeader {
+                leader_hint: Some(leader),
+            } => write!(f, "Not the leader, try node {}", leader),
+            TransferError::NotLeader { leader_hint: None } => write!(f, "Not the leader"),
+            TransferError::UnknownTarget(target) => write!(f, "Node {} is not in the cluster", target),
+            TransferError::TimedOut => write!(f, "Leadership transfer timed out"),
+            TransferError::Aborted => write!(f, "Leadership transfer aborted"),
+            TransferError::Stopped => write!(f, "Node stopped"),
+        }
+    }
+}
+
+impl Error for TransferError {}
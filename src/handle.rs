@@ -0,0 +1,149 @@
+use crate::address_book::NodeId;
+use crate::error::{ProposeError, TransferError};
+use crate::membership::{Membership, MembershipChange};
+use crate::node::Role;
+use crate::proposal::{Proposal, Proposer};
+use crate::rpc::Transport;
+
+use crossbeam_channel::{bounded, Sender};
+use log::error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// Snapshot of a running node's Raft state
+#[derive(PartialEq, Clone, Debug)]
+pub struct Status {
+    pub id: NodeId,
+    pub addr: Option<SocketAddr>, // None if the transport does not use addresses
+    pub role: Role,
+    pub term: u32,
+    pub leader: Option<NodeId>,
+    pub commit_index: usize,
+    pub last_applied: usize,
+    pub membership: Membership,
+}
+
+// Requests from a NodeHandle to its Raft loop
+pub enum Control {
+    Status(Sender<Status>),
+    TransferLeadership(NodeId, Sender<Result<(), TransferError>>),
+    AbortTransfer,
+    ChangeMembership(MembershipChange, Sender<Result<(), ProposeError>>),
+    Shutdown,
+}
+
+// Owner of a Node running in a background thread. Dropping the handle shuts
+// the node down.
+pub struct NodeHandle<T> {
+    proposer: Proposer<T>,
+    control: Sender<Control>,
+    transport: Arc<dyn Transport>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T> NodeHandle<T> {
+    pub fn new(
+        proposer: Proposer<T>,
+        control: Sender<Control>,
+        transport: Arc<dyn Transport>,
+        thread: JoinHandle<()>,
+    ) -> Self {
+        NodeHandle {
+            proposer,
+            control,
+            transport,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn propose(&self, command: impl Into<Vec<u8>>) -> Result<Proposal<T>, ProposeError> {
+        self.proposer.propose(command)
+    }
+
+    // A proposer that can be moved to other threads
+    pub fn proposer(&self) -> Proposer<T> {
+        self.proposer.clone()
+    }
+
+    // None once the node has stopped
+    pub fn status(&self) -> Option<Status> {
+        let (reply, receiver) = bounded(1);
+        self.control.send(Control::Status(reply)).ok()?;
+        receiver.recv().ok()
+    }
+
+    // Hand leadership over to `target`. Proposals are refused until the
+    // transfer ends, the target is caught up and then told to start an
+    // election. Returns once this node stepped down for it.
+    pub fn transfer_leadership(&self, target: NodeId) -> Result<(), TransferError> {
+        let (reply, receiver) = bounded(1);
+        self.control
+            .send(Control::TransferLeadership(target, reply))
+            .map_err(|_| TransferError::Stopped)?;
+        receiver.recv().map_err(|_| TransferError::Stopped)?
+    }
+
+    // Cancel the leadership transfer in progress, if any, and accept
+    // proposals again
+    pub fn abort_leadership_transfer(&self) {
+        let _ = self.control.send(Control::AbortTransfer);
+    }
+
+    // Replace the voting members of the cluster with `voters`, going through
+    // a joint membership. Only one change at a time, returns once the new
+    // membership is committed.
+    pub fn change_membership(
+        &self,
+        voters: impl IntoIterator<Item = NodeId>,
+    ) -> Result<(), ProposeError> {
+        self.request_membership_change(MembershipChange::Voters(voters.into_iter().collect()))
+    }
+
+    // Add a node that receives the log without voting, e.g. a read replica
+    // or a new server warming up before promotion
+    pub fn add_learner(&self, learner: NodeId, addr: SocketAddr) -> Result<(), ProposeError> {
+        self.set_address(learner, addr);
+        self.request_membership_change(MembershipChange::AddLearner(learner))
+    }
+
+    // Make a learner that has every committed entry a voter
+    pub fn promote_learner(&self, learner: NodeId) -> Result<(), ProposeError> {
+        self.request_membership_change(MembershipChange::PromoteLearner(learner))
+    }
+
+    fn request_membership_change(&self, change: MembershipChange) -> Result<(), ProposeError> {
+        let (reply, receiver) = bounded(1);
+        self.control
+            .send(Control::ChangeMembership(change, reply))
+            .map_err(|_| ProposeError::Stopped)?;
+        receiver.recv().map_err(|_| ProposeError::Stopped)?
+    }
+
+    // Reach node `id` at `addr` from now on. Nodes talking UDP also learn
+    // the address of every node that sends them a message.
+    pub fn set_address(&self, id: NodeId, addr: SocketAddr) {
+        self.transport.set_address(id, addr);
+    }
+
+    // Stop the Raft loop, its timers and its listener, and wait for them
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.control.send(Control::Shutdown);
+            let name = thread.thread().name().unwrap_or_default().to_string();
+            if thread.join().is_err() {
+                error!("{} panicked", name);
+            }
+        }
+    }
+}
+
+impl<T> Drop for NodeHandle<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,8 +1,27 @@
+mod address_book;
+mod config;
 mod error;
+mod handle;
+mod membership;
 mod node;
-mod rpc;
+mod progress;
+mod proposal;
+mod state_machine;
 mod timer;
+#[cfg(test)]
 mod tests;
 mod entry;
+pub mod rpc;
+pub mod storage;
 
-pub use node::Node;
+pub use address_book::{AddressBook, NodeId};
+pub use config::Config;
+pub use entry::{Entry, EntryKind};
+pub use error::{ProposeError, TransferError};
+pub use handle::{NodeHandle, Status};
+pub use membership::{Membership, MembershipChange};
+pub use node::{Node, Role};
+pub use proposal::{Proposal, Proposer};
+pub use rpc::{Codec, TcpTransport, Transport, UdpTransport};
+pub use state_machine::StateMachine;
+pub use storage::{FileStorage, MemStorage, Snapshot, SnapshotMeta, Storage};
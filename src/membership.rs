@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use crate::address_book::NodeId;
+
+// The members of the cluster. A change of voters goes through a joint
+// configuration where `outgoing` holds the previous voters and decisions
+// need a majority of both sets, so the old and the new majorities can never
+// act on their own. Learners receive the log but never vote.
+#[derive(PartialEq, Clone, Default, Deserialize, Serialize, Debug)]
+pub struct Membership {
+    pub voters: BTreeSet<NodeId>,
+    pub outgoing: Option<BTreeSet<NodeId>>,
+    #[serde(default)]
+    pub learners: BTreeSet<NodeId>,
+}
+
+// A change requested through NodeHandle
+pub enum MembershipChange {
+    Voters(BTreeSet<NodeId>),
+    AddLearner(NodeId),
+    PromoteLearner(NodeId),
+}
+
+impl Membership {
+    pub fn new(voters: impl IntoIterator<Item = NodeId>) -> Membership {
+        Membership {
+            voters: voters.into_iter().collect(),
+            outgoing: None,
+            learners: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.outgoing.is_some()
+    }
+
+    pub fn is_voter(&self, id: &NodeId) -> bool {
+        self.voters.contains(id) || self.outgoing.as_ref().is_some_and(|old| old.contains(id))
+    }
+
+    pub fn is_learner(&self, id: &NodeId) -> bool {
+        self.learners.contains(id) && !self.is_voter(id)
+    }
+
+    // Every node the leader replicates to
+    pub fn members(&self) -> BTreeSet<NodeId> {
+        let mut members = self.voters.clone();
+        if let Some(old) = &self.outgoing {
+            members.extend(old);
+        }
+        members.extend(&self.learners);
+        members
+    }
+
+    // The joint configuration moving from the current voters to `voters`,
+    // learners among them become voters
+    pub fn enter_joint(&self, voters: BTreeSet<NodeId>) -> Membership {
+        Membership {
+            learners: self.learners.difference(&voters).copied().collect(),
+            voters,
+            outgoing: Some(self.voters.clone()),
+        }
+    }
+
+    // The configuration completing a change
+    pub fn leave_joint(&self) -> Membership {
+        Membership {
+            voters: self.voters.clone(),
+            outgoing: None,
+            learners: self.learners.clone(),
+        }
+    }
+
+    // Learners do not take part in decisions, no joint step is needed
+    pub fn add_learner(&self, learner: NodeId) -> Membership {
+        let mut membership = self.clone();
+        membership.learners.insert(learner);
+        membership
+    }
+
+    // Whether the nodes for which `granted` holds are a majority of the
+    // voters, and of the outgoing voters during a change
+    pub fn has_quorum(&self, granted: impl Fn(&NodeId) -> bool) -> bool {
+        is_majority(&self.voters, &granted)
+            && self.outgoing.as_ref().is_none_or(|old| is_majority(old, &granted))
+    }
+}
+
+fn is_majority(voters: &BTreeSet<NodeId>, granted: &impl Fn(&NodeId) -> bool) -> bool {
+    voters.iter().filter(|voter| granted(voter)).count() > voters.len() / 2
+}
@@ -1,129 +1,198 @@
-use crate::error::InitializationError;
-use crate::timer::NodeTimer;
+use crate::address_book::{AddressBook, NodeId};
+use crate::config::Config;
+use crate::error::{InitializationError, ProposeError, TransferError};
+use crate::handle::{Control, NodeHandle, Status};
+use crate::membership::{Membership, MembershipChange};
+use crate::progress::{Progress, ProgressState};
+use crate::proposal::{Completion, ProposalRequest, Proposer};
+use crate::timer::{NodeTimer, ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN};
 use crate::rpc::*;
-use crate::entry::Entry;
+use crate::state_machine::StateMachine;
+use crate::entry::{Entry, EntryKind};
+use crate::storage::{decode_record, encode_record, HardState, Snapshot, SnapshotMeta, Storage};
 
-use crossbeam_channel::{select, unbounded};
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
 use log::{info, error};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-#[macro_use]
 use crate::*;
 
-struct ClusterInfo {
-    node_number: u32,
-    majority_number: u32,
-    heartbeat_interval: u32,
-    node_list: Vec<String>, // Vec("host:port")
-}
-
-impl ClusterInfo {
-    fn new(node_number: u32, heartbeat_interval: u32, node_list: Vec<String>) -> ClusterInfo {
-        let majority_number = (node_number - 1) / 2 + 1;
-
-        ClusterInfo {
-            node_number,
-            majority_number,
-            heartbeat_interval,
-            node_list,
-        }
-    }
+// A leadership transfer in progress on the leader
+struct LeaderTransfer {
+    target: NodeId,
+    elapsed: u32, // milliseconds since it started
+    timeout_sent: bool,
+    reply: Sender<Result<(), TransferError>>,
 }
 
 // Role of a Node
-#[derive(PartialEq, Copy, Clone)]
-enum Role {
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Role {
     Follower,
+    Learner,      // receives the log but never votes
+    PreCandidate, // asking for pre-votes, the term is unchanged
     Candidate,
     Leader,
 }
 
-pub struct Node {
-    cluster_info: ClusterInfo,
+pub struct Node<S: Storage, M: StateMachine, T: Transport = UdpTransport> {
+    id: NodeId,
+    config: Config,
+    membership: Membership,
+    membership_index: usize, // index of the entry that set it, 0 if bootstrapped
+    bootstrap: Membership,   // the nodes given to new(), until a change is logged
+    // completion of the membership change started on this node
+    membership_change: Option<Sender<Result<(), ProposeError>>>,
     role: Role,
     current_term: u32,
-    candidated_addr: Option<SocketAddr>,
-    leader_addr: Option<SocketAddr>,
-    votes: u32,
-    logs: Vec<Entry>,
+    candidate_id: Option<NodeId>,
+    leader_id: Option<NodeId>,
+    leader_contact: Option<Instant>, // last message from the current leader
+    votes: HashSet<NodeId>, // granted in the current (pre-)election
+    storage: S,
+    log_bytes: usize, // size of the entries after the snapshot
+    state_machine: M,
+    proposal_sender: Sender<ProposalRequest<M::Output>>,
+    proposal_receiver: Receiver<ProposalRequest<M::Output>>,
+    // completion of the entries proposed on this node, by index
+    pending: HashMap<usize, (u32, Completion<M::Output>)>,
+    control_sender: Sender<Control>,
+    control_receiver: Receiver<Control>,
+    incoming_snapshot: Option<Snapshot>, // chunks received so far from the leader
     commit_index: usize,
-    last_applied: u32,
-    next_index: HashMap<SocketAddr, usize>,
-    match_index: HashMap<SocketAddr, usize>,
-    pub rpc: Rpc,
+    last_applied: usize,
+    pub(crate) progress: HashMap<NodeId, Progress>, // replication state of each follower
+    quorum_check_elapsed: u64, // milliseconds since the leader last checked its quorum
+    transfer: Option<LeaderTransfer>,
+    peers: Vec<NodeId>, // the other members, receiving broadcasts
+    pub rpc: Rpc<T>,
     timer: NodeTimer,
 }
 
-impl Node {
+impl<S: Storage, M: StateMachine> Node<S, M> {
+    // A node exchanging UDP datagrams on host:port, `node_list` holds the id
+    // and address of the other initial members
     pub fn new(
+        id: NodeId,
         host: String,
         port: u16,
-        node_number: u32,
-        heartbeat_interval: u32,
-        node_list: Vec<String>,
-    ) -> Result<Node, Box<dyn Error>> {
+        node_list: Vec<(NodeId, String)>,
+        storage: S,
+        state_machine: M,
+        config: Config,
+    ) -> Result<Node<S, M>, Box<dyn Error>> {
         if let Some(socket_addr) = format!("{}:{}", host, port).to_socket_addrs()?.next() {
-            let mut peer_list: Vec<SocketAddr> = Vec::new();
-            let mut next_index: HashMap<SocketAddr, usize> = HashMap::new();
-            let mut match_index: HashMap<SocketAddr, usize> = HashMap::new();
-            for peer in &node_list {
-                let peer = peer.as_str().to_socket_addrs()?.next().unwrap();
-                peer_list.push(peer.clone());
-                next_index.insert(peer.clone(), 0);
-                match_index.insert(peer.clone(), 0);
-            }
-            let cs = Arc::new(RPCCS::new(socket_addr, peer_list)?);
-            let (rpc_tx, rpc_rx) = unbounded();
-            let mut log_vec = Vec::<Entry>::new(); //加入哨兵防止第一次AppendEntriesRequest没有记录
-            log_vec.push(Entry {
-                index: 1,
-                term: 0,
-                command: String::new(),
-            });
-            return Ok(Node {
-                cluster_info: ClusterInfo::new(node_number, heartbeat_interval, node_list),
-                role: Role::Follower,
-                current_term: 0,
-                candidated_addr: None,
-                leader_addr: None,
-                votes: 0,
-                logs: log_vec,
-                commit_index: 1,
-                last_applied: 1,
-                next_index,
-                match_index,
-                rpc: Rpc {
-                    cs,
-                    notifier: Some(rpc_tx),
-                    receiver: Some(rpc_rx),
-                },
-                timer: NodeTimer::new(heartbeat_interval)?,
-            });
+            let addresses = AddressBook::new([(id, socket_addr)]);
+            for (peer, peer_addr) in &node_list {
+                addresses.set(*peer, peer_addr.as_str().to_socket_addrs()?.next().unwrap());
+            }
+            let transport = UdpTransport::bind(id, socket_addr, addresses, config.codec)?;
+            let peers = node_list.iter().map(|(peer, _)| *peer).collect();
+            return Node::with_transport(id, transport, peers, storage, state_machine, config);
         }
         Err(Box::new(InitializationError::NodeInitializationError))
     }
+}
 
-    fn start_rpc_listener(&mut self) -> Result<(), Box<dyn Error>> {
+impl<S: Storage, M: StateMachine, T: Transport> Node<S, M, T> {
+    // A node talking to the other initial members `peers` through `transport`
+    pub fn with_transport(
+        id: NodeId,
+        transport: T,
+        peers: Vec<NodeId>,
+        storage: S,
+        mut state_machine: M,
+        config: Config,
+    ) -> Result<Node<S, M, T>, Box<dyn Error>> {
+        let bootstrap = Membership::new(peers.into_iter().chain([id]));
+        let (rpc_tx, rpc_rx) = unbounded();
+        // Restore the state persisted before the last shutdown or crash
+        let saved = storage.hard_state();
+        let snapshot = storage.snapshot();
+        let snapshot_index = snapshot.meta.last_included_index;
+        if snapshot_index > 0 {
+            state_machine.restore(&snapshot.data)?;
+        }
         info!(
-            "Starting RPC Server/Client on {}",
-            self.rpc.cs.socket_addr
+            "{} restored term {}, voted for {:?}, log [{}, {}], commit index {}",
+            id,
+            saved.term,
+            saved.voted_for,
+            storage.first_index(),
+            storage.last_index(),
+            saved.commit_index
         );
+        let log_bytes = log_bytes(&storage)?;
+        let (proposal_sender, proposal_receiver) = unbounded();
+        let (control_sender, control_receiver) = unbounded();
+        let mut node = Node {
+            id,
+            timer: NodeTimer::new(config.heartbeat_interval)?,
+            config,
+            membership: Membership::default(),
+            membership_index: 0,
+            bootstrap,
+            membership_change: None,
+            role: Role::Follower,
+            current_term: saved.term,
+            candidate_id: saved.voted_for,
+            leader_id: None,
+            leader_contact: None,
+            votes: HashSet::new(),
+            storage,
+            log_bytes,
+            state_machine,
+            proposal_sender,
+            proposal_receiver,
+            pending: HashMap::new(),
+            control_sender,
+            control_receiver,
+            incoming_snapshot: None,
+            commit_index: saved.commit_index.max(snapshot_index),
+            last_applied: snapshot_index,
+            progress: HashMap::new(),
+            quorum_check_elapsed: 0,
+            transfer: None,
+            peers: Vec::new(),
+            rpc: Rpc {
+                transport: Arc::new(transport),
+                notifier: Some(rpc_tx),
+                receiver: Some(rpc_rx),
+                listener: None,
+            },
+        };
+        node.reload_membership();
+        Ok(node)
+    }
+
+    // Start outside of the cluster instead of as one of its initial members,
+    // for a server joining a running cluster: the leader adds it with
+    // add_learner, and it never campaigns before a membership making it a
+    // voter reaches its log. `peers` given to the constructor are only used
+    // as addresses.
+    pub fn joining(mut self) -> Self {
+        self.bootstrap = Membership::default();
+        self.reload_membership();
+        self
+    }
+
+    fn start_rpc_listener(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Starting RPC Server/Client of {}", self.id);
         if let Some(rpc_notifier) = self.rpc.notifier.take() {
-            let rpc_cs = Arc::clone(&self.rpc.cs);
-            thread::spawn(move || match rpc_cs.start_listener(rpc_notifier) {
+            let transport = Arc::clone(&self.rpc.transport);
+            let id = self.id;
+            self.rpc.listener = Some(thread::spawn(move || match transport.listen(rpc_notifier) {
                 Ok(()) => Ok(()),
                 Err(error) => {
-                    error!(
-                        "{} RPC Clent/Server error: {}",
-                        rpc_cs.socket_addr.port(), error
-                    );
-                    Err(Box::new(InitializationError::RPCInitializationError))
+                    error!("{} RPC Clent/Server error: {}", id, error);
+                    Err(InitializationError::RPCInitializationError)
                 }
-            });
+            }));
         };
         Ok(())
     }
@@ -132,19 +201,12 @@ impl Node {
         info!("Starting Raft Algorithm");
         self.timer.run_elect();
         loop {
-            if self.role == Role::Leader {
-                info!("leader {} closed", self.rpc.cs.socket_addr.port());
-                break;
-            }
             select! {
                 recv(self.rpc.receiver.as_ref().unwrap()) -> msg => {
                     // Handle the RPC request
                     let msg = msg?;
-                    info!(
-                        "{} receive RPC request: {:?}", 
-                        self.rpc.cs.socket_addr.port(), msg.message
-                    );
-                    match msg.message {
+                    info!("{} receive RPC request: {:?}", self.id, msg);
+                    match msg {
                         Message::AppendEntriesRequest(request) => {
                             self.handle_append_entries_request(request);
                         },
@@ -157,176 +219,1304 @@ impl Node {
                         Message::RequestVoteResponse(request) => {
                             self.handle_request_vote_response(request);
                         },
+                        Message::PreVoteRequest(request) => {
+                            self.handle_pre_vote_request(request);
+                        },
+                        Message::PreVoteResponse(request) => {
+                            self.handle_pre_vote_response(request);
+                        },
+                        Message::TimeoutNow(request) => {
+                            self.handle_timeout_now(request);
+                        },
+                        Message::InstallSnapshotRequest(request) => {
+                            self.handle_install_snapshot_request(request);
+                        },
+                        Message::InstallSnapshotResponse(request) => {
+                            self.handle_install_snapshot_response(request);
+                        },
+                    }
+                }
+                recv(self.proposal_receiver) -> request => {
+                    // the node holds a sender, so the channel is never closed
+                    let requests = self.proposal_batch(request.unwrap());
+                    self.handle_proposals(requests);
+                }
+                recv(self.timer.receiver) -> generation => {
+                    // ticks queued before the timer was restarted are stale
+                    if self.timer.is_current(generation?) {
+                        self.handle_timeout();
                     }
                 }
-                recv(self.timer.receiver) -> _ => {
-                    info!("{} timeout occur", self.rpc.cs.socket_addr.port());
-                    self.handle_timeout();
+                recv(self.control_receiver) -> control => {
+                    // the node holds a sender, so the channel is never closed
+                    match control.unwrap() {
+                        Control::Status(reply) => {
+                            let _ = reply.send(self.status());
+                        }
+                        Control::TransferLeadership(target, reply) => {
+                            self.handle_transfer_leadership(target, reply);
+                        }
+                        Control::AbortTransfer => {
+                            self.finish_transfer(Err(TransferError::Aborted));
+                        }
+                        Control::ChangeMembership(voters, reply) => {
+                            self.handle_change_membership(voters, reply);
+                        }
+                        Control::Shutdown => {
+                            info!("{} shutting down", self.id);
+                            break;
+                        }
+                    }
                 }
             }
+            self.apply_committed();
+            self.maybe_finish_membership_change();
+            self.maybe_snapshot();
         }
         Ok(())
     }
 
-    fn handle_append_entries_request(&mut self, mut msg: AppendEntriesRequest) {
-        match self.role {
-            Role::Follower => {
-                self.timer.reset_elect();
-
-                if !msg.entries.is_empty() {
-                    let success: bool = if msg.term < self.current_term
-                    || msg.prev_log_index >= self.logs.len()
-                    || msg.entries[msg.prev_log_index].term != self.logs[msg.prev_log_index].term
-                    {
-                        false
-                    } else {
-                        self.logs.pop();
-                        self.logs.append(&mut msg.entries);
-                        if msg.leader_commit > self.commit_index {
-                            self.commit_index = if msg.leader_commit < self.logs.len() {
-                                msg.leader_commit
-                            } else {
-                                self.logs.len()
-                            };
-                            true
-                        } else {
-                            false
-                        }
-                    };
-                    append_entries_response!(&self, success, msg.leader_addr);
+    fn handle_append_entries_request(&mut self, msg: AppendEntriesRequest) {
+        if msg.term < self.current_term {
+            // tell the stale leader about the newer term
+            append_entries_response!(&self, false, msg.leader_id, 0, (None, 0));
+            return;
+        }
+        if msg.term > self.current_term || !self.is_follower() {
+            // a leader exists in this term, candidates give up
+            if !self.become_follower(msg.term) {
+                return;
+            }
+        }
+        self.timer.reset_elect();
+        self.leader_id = Some(msg.leader_id);
+        self.leader_contact = Some(Instant::now());
+
+        // entries up to the snapshot are committed, so they match
+        let prev_log_matches = msg.prev_log_index < self.storage.first_index() - 1
+            || self.storage.term(msg.prev_log_index) == Some(msg.prev_log_term);
+        let last_new_index = msg.prev_log_index + msg.entries.len();
+        if prev_log_matches {
+            if let Err(error) = self.append_entries(msg.entries) {
+                error!(
+                    "{} failed to persist log entries: {}",
+                    self.id, error
+                );
+                return;
+            }
+            if msg.leader_commit > self.commit_index {
+                self.commit_index = self.commit_index.max(msg.leader_commit.min(last_new_index));
+                self.save_hard_state();
+            }
+        }
+        if prev_log_matches {
+            append_entries_response!(&self, true, msg.leader_id, last_new_index, (None, 0));
+        } else {
+            let conflict = self.conflict_hint(msg.prev_log_index);
+            append_entries_response!(&self, false, msg.leader_id, 0, conflict);
+        }
+    }
+
+    // Where the leader should retry after our entry at `prev_log_index`
+    // did not match: past the end of a short log, or at the first entry of
+    // the conflicting term so the whole term is skipped in one round trip
+    pub(crate) fn conflict_hint(&self, prev_log_index: usize) -> (Option<u32>, usize) {
+        let last_index = self.storage.last_index();
+        if prev_log_index > last_index {
+            return (None, last_index + 1);
+        }
+        let conflict_term = self.storage.term(prev_log_index);
+        let first_index = self.storage.first_index();
+        let mut conflict_index = prev_log_index;
+        while conflict_index > first_index && self.storage.term(conflict_index - 1) == conflict_term {
+            conflict_index -= 1;
+        }
+        (conflict_term, conflict_index)
+    }
+
+    pub(crate) fn handle_append_entries_response(&mut self, msg: AppendEntriesResponse) {
+        if msg.term > self.current_term {
+            self.become_follower(msg.term);
+            return;
+        }
+        if self.role != Role::Leader || msg.term < self.current_term {
+            return;
+        }
+        let peer = msg.node_id;
+        let progress = match self.progress.get_mut(&peer) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.recent_active = true;
+        if progress.state == ProgressState::Snapshot {
+            return;
+        }
+        if msg.success {
+            if !progress.update(msg.match_index) {
+                // responses may arrive out of order
+                return;
+            }
+            if progress.state == ProgressState::Probe {
+                progress.become_replicate();
+            }
+            self.advance_commit_index();
+            self.maybe_send_timeout_now(false);
+        } else {
+            // skip the follower's conflicting term, or jump right after our
+            // last entry of that term if we have it too
+            let hint = msg
+                .conflict_term
+                .and_then(|term| self.last_index_of_term(term))
+                .map_or(msg.conflict_index, |index| index + 1);
+            let progress = self.progress.get_mut(&peer).unwrap();
+            let next_index = progress.next_index.saturating_sub(1).min(hint);
+            progress.become_probe(next_index);
+        }
+        self.replicate_to(peer);
+    }
+
+    pub(crate) fn handle_transfer_leadership(
+        &mut self,
+        target: NodeId,
+        reply: Sender<Result<(), TransferError>>,
+    ) {
+        if self.role != Role::Leader {
+            let _ = reply.send(Err(TransferError::NotLeader {
+                leader_hint: self.leader_id,
+            }));
+            return;
+        }
+        if target == self.id {
+            let _ = reply.send(Ok(()));
+            return;
+        }
+        if !self.membership.is_voter(&target) {
+            let _ = reply.send(Err(TransferError::UnknownTarget(target)));
+            return;
+        }
+        // a newer request replaces the one in progress
+        self.finish_transfer(Err(TransferError::Aborted));
+        info!(
+            "{} transfers leadership to {}",
+            self.id, target
+        );
+        self.transfer = Some(LeaderTransfer {
+            target,
+            elapsed: 0,
+            timeout_sent: false,
+            reply,
+        });
+        self.maybe_send_timeout_now(false);
+        self.replicate_to(target);
+    }
+
+    // Tell the transfer target to start an election once it has every entry.
+    // The message may be lost, so it is sent again on every heartbeat until
+    // the transfer ends.
+    fn maybe_send_timeout_now(&mut self, heartbeat: bool) {
+        let target = match &self.transfer {
+            Some(transfer) if heartbeat || !transfer.timeout_sent => transfer.target,
+            _ => return,
+        };
+        match self.progress.get(&target) {
+            Some(progress) if progress.match_index >= self.storage.last_index() => {}
+            _ => return,
+        }
+        timeout_now!(&self, target);
+        self.transfer.as_mut().unwrap().timeout_sent = true;
+    }
+
+    fn finish_transfer(&mut self, result: Result<(), TransferError>) {
+        if let Some(transfer) = self.transfer.take() {
+            if let Err(error) = &result {
+                info!(
+                    "{} leadership transfer to {} failed: {}",
+                    self.id, transfer.target, error
+                );
+            }
+            let _ = transfer.reply.send(result);
+        }
+    }
+
+    // The leader picked us to take over, campaign without waiting for the
+    // election timeout or asking for pre-votes
+    fn handle_timeout_now(&mut self, msg: TimeoutNow) {
+        if msg.term != self.current_term
+            || self.role == Role::Leader
+            || !self.membership.is_voter(&self.id)
+        {
+            return;
+        }
+        info!(
+            "{} told to campaign by {}",
+            self.id, msg.leader_id
+        );
+        self.start_election(true);
+    }
+
+    pub(crate) fn last_index_of_term(&self, term: u32) -> Option<usize> {
+        let first_index = self.storage.first_index();
+        (first_index..=self.storage.last_index())
+            .rev()
+            .map(|index| (index, self.storage.term(index)))
+            .take_while(|&(_, entry_term)| entry_term >= Some(term))
+            .find(|&(_, entry_term)| entry_term == Some(term))
+            .map(|(index, _)| index)
+    }
+
+    // Send `peer` as many entries as its in-flight window allows, or start
+    // sending the snapshot if they were compacted
+    fn replicate_to(&mut self, peer: NodeId) {
+        loop {
+            let progress = &self.progress[&peer];
+            if progress.is_paused(self.config.max_inflight_msgs, self.config.max_inflight_bytes) {
+                return;
+            }
+            let next_index = progress.next_index;
+            if next_index < self.storage.first_index() {
+                self.send_snapshot(peer);
+                return;
+            }
+            if progress.state == ProgressState::Replicate && next_index > self.storage.last_index() {
+                return;
+            }
+            let last_index = self
+                .storage
+                .last_index()
+                .min(next_index - 1 + self.config.max_append_entries);
+            match self.send_entries(peer, next_index - 1, last_index) {
+                Some((last_index, bytes)) => {
+                    self.progress.get_mut(&peer).unwrap().sent(last_index, bytes)
                 }
-                self.leader_addr = Some(msg.leader_addr);
-                self.candidated_addr = None;
+                None => return,
             }
-            Role::Candidate => {
-                if msg.term >= self.current_term {
-                    self.current_term = msg.term;
-                    self.change_role_to(Role::Follower);
-                    self.timer.run_elect();
-                    // let follower handle it
-                    // self.handle_append_entries_request(msg);
+        }
+    }
+
+    // Send the entries in (prev_log_index, last_index] that fit in
+    // max_append_bytes, returns the index of the last one sent and their
+    // encoded size, or None if they could not be read
+    fn send_entries(
+        &self,
+        peer: NodeId,
+        prev_log_index: usize,
+        last_index: usize,
+    ) -> Option<(usize, usize)> {
+        let prev_log_term = self.storage.term(prev_log_index).unwrap_or(0);
+        let mut entries = match self.storage.entries(prev_log_index + 1, last_index + 1) {
+            Ok(entries) => entries,
+            Err(error) => {
+                error!(
+                    "{} failed to read entries for {}: {}",
+                    self.id, peer, error
+                );
+                return None;
+            }
+        };
+        // an entry larger than the limit is still sent on its own
+        let mut bytes = 0;
+        let mut count = 0;
+        for entry in &entries {
+            let size = self.config.codec.encoded_size(entry);
+            if count > 0 && bytes + size > self.config.max_append_bytes {
+                break;
+            }
+            bytes += size;
+            count += 1;
+        }
+        entries.truncate(count);
+        let message = Message::AppendEntriesRequest(AppendEntriesRequest::new(
+            self.current_term,
+            self.id,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            self.commit_index,
+        ));
+        if let Err(error) = self.rpc.transport.send_to(peer, message) {
+            // the entries are retried later like lost ones, a plain heartbeat
+            // still keeps the follower from starting an election
+            error!("{} failed to send entries to {}: {}", self.id, peer, error);
+            append_entries_request!(&self, peer, prev_log_index, prev_log_term, Vec::new());
+        }
+        Some((prev_log_index + count, bytes))
+    }
+
+    // End of the snapshot chunk starting at `offset`: as much data as takes
+    // at most snapshot_chunk_size bytes encoded with the codec, and at least
+    // one byte so the transfer moves on
+    fn snapshot_chunk_end(&self, data: &[u8], offset: usize) -> usize {
+        let chunk_size = self.config.snapshot_chunk_size;
+        // no codec takes less than a byte per byte of data
+        let mut end = (offset + chunk_size).min(data.len());
+        loop {
+            let size = self.config.codec.encoded_size(&&data[offset..end]);
+            if size <= chunk_size || end <= offset + 1 {
+                return end;
+            }
+            // shrink in proportion to the overshoot, a few rounds at most
+            end = offset + ((end - offset) * chunk_size / size).max(1);
+        }
+    }
+
+    pub(crate) fn send_snapshot(&mut self, peer: NodeId) {
+        let snapshot = self.storage.snapshot();
+        let snapshot_index = snapshot.meta.last_included_index;
+        let progress = self.progress.get_mut(&peer).unwrap();
+        if progress.state != ProgressState::Snapshot || progress.snapshot_index != snapshot_index {
+            // a newer snapshot replaced the one being sent, start it over
+            progress.become_snapshot(snapshot_index);
+        }
+        let offset = progress.snapshot_offset.min(snapshot.data.len());
+        install_snapshot_request!(&self, peer, snapshot, offset);
+    }
+
+    fn replicate(&mut self) {
+        let peers: Vec<NodeId> = self.progress.keys().copied().collect();
+        for peer in peers {
+            self.replicate_to(peer);
+        }
+    }
+
+    // Heartbeat every follower, resending whatever may have been lost
+    fn broadcast_heartbeat(&mut self) {
+        let peers: Vec<NodeId> = self.progress.keys().copied().collect();
+        for peer in peers {
+            let progress = self.progress.get_mut(&peer).unwrap();
+            progress.tick();
+            match progress.state {
+                ProgressState::Probe => self.replicate_to(peer),
+                ProgressState::Replicate => {
+                    // entries up to match_index are known to be there
+                    let match_index = progress.match_index;
+                    if self.storage.term(match_index).is_some() {
+                        self.send_entries(peer, match_index, match_index);
+                    }
+                    self.replicate_to(peer);
                 }
+                ProgressState::Snapshot => self.send_snapshot(peer),
             }
-            Role::Leader => {
-                if self.current_term <= msg.term {
-                    self.timer.stop_heartbeat();
-                    self.change_role_to(Role::Follower);
-                    self.timer.run_elect();
+        }
+    }
+
+    // Commit the highest entry of the current term stored on a majority
+    fn advance_commit_index(&mut self) {
+        for i in (self.commit_index + 1..=self.storage.last_index()).rev() {
+            if self.storage.term(i) != Some(self.current_term) {
+                // entries of previous terms are only committed indirectly
+                break;
+            }
+            let stored = |id: &NodeId| {
+                *id == self.id
+                    || self.progress.get(id).is_some_and(|progress| progress.match_index >= i)
+            };
+            if self.membership.has_quorum(stored) {
+                self.commit_index = i;
+                self.save_hard_state();
+                break;
+            }
+        }
+    }
+
+    // Coalesce `first` with the proposals already queued behind it, up to
+    // max_append_entries commands or max_append_bytes
+    fn proposal_batch(&self, first: ProposalRequest<M::Output>) -> Vec<ProposalRequest<M::Output>> {
+        let mut bytes = first.command.len();
+        let mut requests = vec![first];
+        while requests.len() < self.config.max_append_entries && bytes < self.config.max_append_bytes {
+            match self.proposal_receiver.try_recv() {
+                Ok(request) => {
+                    bytes += request.command.len();
+                    requests.push(request);
                 }
+                Err(_) => break,
             }
         }
+        requests
     }
 
-    fn handle_append_entries_response(&mut self, msg: AppendEntriesResponse) {
-        match self.role {
-            Role::Follower => {}
-            Role::Candidate => {}
-            Role::Leader => {
-                if msg.success {
-                    *self.next_index.entry(msg.socket_addr).or_insert(0) = msg.next_index;
-                    *self.match_index.entry(msg.socket_addr).or_insert(0) = msg.match_index;
-
-                    let mut i = self.commit_index;
-                    loop {
-                        let mut match_count = 1;
-                        for val in self.match_index.values() {
-                            if val >= &i && self.logs[i].term == self.current_term {
-                                match_count += 1;
-                            }
-                        }
-                        if match_count >= self.cluster_info.majority_number {
-                            i += 1;
-                        } else {
-                            break;
-                        }
-                    }
-                    self.commit_index = i;
+    // Most bytes an encoded entry can take for an AppendEntriesRequest
+    // carrying it to fit in a message of the transport, sized for the
+    // largest indexes and terms
+    fn max_entry_size(&self) -> Option<usize> {
+        let max = self.rpc.transport.max_message_size()?;
+        let from_addr = self
+            .rpc
+            .transport
+            .local_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let heartbeat = AppendEntriesRequest::new(
+            u32::MAX,
+            self.id,
+            usize::MAX,
+            u32::MAX,
+            Vec::new(),
+            usize::MAX,
+        );
+        let message = RPCMessage::new(self.id, from_addr, Message::AppendEntriesRequest(heartbeat));
+        Some(max.saturating_sub(self.config.codec.encoded_size(&message)))
+    }
+
+    // Append a batch of proposals to the log with a single storage write
+    pub(crate) fn handle_proposals(&mut self, requests: Vec<ProposalRequest<M::Output>>) {
+        if self.transfer.is_some() {
+            for request in requests {
+                let _ = request.reply.send(Err(ProposeError::TransferringLeadership));
+            }
+            return;
+        }
+        if self.role != Role::Leader {
+            for request in requests {
+                let _ = request.reply.send(Err(ProposeError::NotLeader {
+                    leader_hint: self.leader_id,
+                }));
+            }
+            return;
+        }
+        // an entry no message can carry would never reach the followers
+        let max_entry_size = self.max_entry_size();
+        let requests: Vec<_> = requests
+            .into_iter()
+            .filter(|request| {
+                let max = match max_entry_size {
+                    Some(max) => max,
+                    None => return true,
+                };
+                let size = self.config.codec.encoded_size(&Entry {
+                    index: usize::MAX,
+                    term: self.current_term,
+                    kind: EntryKind::Normal,
+                    command: request.command.clone(),
+                });
+                if size > max {
+                    let _ = request.reply.send(Err(ProposeError::TooLarge(size)));
+                    return false;
                 }
+                true
+            })
+            .collect();
+        if requests.is_empty() {
+            return;
+        }
+        let first_index = self.storage.last_index() + 1;
+        let (entries, requests): (Vec<Entry>, Vec<_>) = requests
+            .into_iter()
+            .zip(first_index..)
+            .map(|(request, index)| {
+                let entry = Entry {
+                    index,
+                    term: self.current_term,
+                    kind: EntryKind::Normal,
+                    command: request.command,
+                };
+                (entry, (request.reply, request.done))
+            })
+            .unzip();
+        if let Err(error) = self.append_entries(entries) {
+            error!(
+                "{} failed to persist proposed entries: {}",
+                self.id, error
+            );
+            for (reply, _) in requests {
+                let _ = reply.send(Err(ProposeError::StorageFailure));
             }
+            return;
+        }
+        for ((reply, done), index) in requests.into_iter().zip(first_index..) {
+            self.pending.insert(index, (self.current_term, done));
+            let _ = reply.send(Ok((index, self.current_term)));
         }
+
+        self.replicate();
+        self.advance_commit_index();
+    }
+
+    // Get a handle to submit commands to this node from other threads
+    pub fn proposer(&self) -> Proposer<M::Output> {
+        Proposer::new(self.proposal_sender.clone())
     }
 
     fn handle_request_vote_request(&mut self, msg: RequestVoteRequest) {
+        if msg.term > self.current_term && !msg.leadership_transfer && self.leader_alive() {
+            // e.g. a node removed from the cluster that timed out, it must
+            // not depose a leader that still reaches a majority
+            info!(
+                "{} ignores vote request of {}, the leader is alive",
+                self.id, msg.candidate_id
+            );
+            return;
+        }
+        if msg.term > self.current_term && !self.become_follower(msg.term) {
+            return;
+        }
+        let vote_granted = msg.term == self.current_term
+            && self.role != Role::Learner
+            && (self.candidate_id.is_none() || self.candidate_id == Some(msg.candidate_id))
+            && self.log_up_to_date(msg.last_log_index, msg.last_log_term);
+        if vote_granted {
+            // the vote must be on disk before the candidate learns about it
+            if !self.set_term_and_vote(self.current_term, Some(msg.candidate_id)) {
+                return;
+            }
+            self.timer.reset_elect();
+        }
+        vote_for!(&self, vote_granted, msg.candidate_id);
+    }
+
+    fn handle_request_vote_response(&mut self, msg: RequestVoteResponse) {
+        if msg.term > self.current_term {
+            self.become_follower(msg.term);
+            return;
+        }
+        // votes from an earlier election do not count
+        if self.role != Role::Candidate || msg.term < self.current_term || !msg.vote_granted {
+            return;
+        }
+        self.votes.insert(msg.node_id);
+        info!("{} gets {} votes", self.id, self.votes.len());
+        if self.membership.has_quorum(|id| self.votes.contains(id)) {
+            self.become_leader();
+        }
+    }
+
+    // Whether a log ending at `last_log_index` in `last_log_term` holds every
+    // entry of ours
+    fn log_up_to_date(&self, last_log_index: usize, last_log_term: u32) -> bool {
+        let our_last_term = self.storage.last_term();
+        last_log_term > our_last_term
+            || (last_log_term == our_last_term && last_log_index >= self.storage.last_index())
+    }
+
+    // Grant a pre-vote if we would grant the vote and have not heard from a
+    // leader within the minimum election timeout. Nothing is persisted.
+    fn handle_pre_vote_request(&mut self, msg: PreVoteRequest) {
+        let vote_granted = msg.term > self.current_term
+            && !self.leader_alive()
+            && self.role != Role::Learner
+            && self.log_up_to_date(msg.last_log_index, msg.last_log_term);
+        let term = if vote_granted { msg.term } else { self.current_term };
+        pre_vote_for!(&self, term, vote_granted, msg.candidate_id);
+    }
+
+    // Whether we lead or heard from the leader within the minimum election
+    // timeout, in which case no election is needed
+    fn leader_alive(&self) -> bool {
+        self.role == Role::Leader
+            || self.leader_contact.is_some_and(|contact| {
+                contact.elapsed() < Duration::from_millis(ELECTION_TIMEOUT_MIN)
+            })
+    }
+
+    fn handle_pre_vote_response(&mut self, msg: PreVoteResponse) {
+        if msg.term > self.current_term && !msg.vote_granted {
+            self.become_follower(msg.term);
+            return;
+        }
+        if self.role != Role::PreCandidate || msg.term != self.current_term + 1 || !msg.vote_granted {
+            return;
+        }
+        self.votes.insert(msg.node_id);
+        info!("{} gets {} pre-votes", self.id, self.votes.len());
+        if self.membership.has_quorum(|id| self.votes.contains(id)) {
+            self.start_election(false);
+        }
+    }
+
+    pub(crate) fn handle_timeout(&mut self) {
         match self.role {
-            Role::Follower => {
-                self.timer.reset_elect();
-                if msg.term >= self.current_term
-                && (self.candidated_addr.is_none() || self.candidated_addr.unwrap() == msg.candidated_addr)
-                && msg.last_log_term >= self.logs.last().unwrap().term
-                && msg.last_log_index >= self.logs.last().unwrap().index {
-                    self.current_term = msg.term;
-                    vote_for!(&self, true, msg.candidated_addr);
-                    self.candidated_addr = Some(msg.candidated_addr);
+            Role::Follower | Role::Learner | Role::PreCandidate | Role::Candidate => {
+                if !self.membership.is_voter(&self.id) {
+                    // removed from the cluster or not added yet
+                    self.timer.run_elect();
+                } else if self.config.pre_vote {
+                    self.start_pre_vote();
                 } else {
-                    vote_for!(&self, false, msg.candidated_addr);
+                    self.start_election(false);
                 }
             }
-            Role::Candidate => {
-                if msg.term > self.current_term {
-                    self.current_term = msg.term;
-                    self.change_role_to(Role::Follower);
-                    self.timer.run_elect();
+            Role::Leader => {
+                if self.config.check_quorum && !self.check_quorum() {
+                    return;
+                }
+                if let Some(transfer) = self.transfer.as_mut() {
+                    transfer.elapsed += self.config.heartbeat_interval;
+                    if transfer.elapsed >= self.config.transfer_timeout {
+                        self.finish_transfer(Err(TransferError::TimedOut));
+                    }
                 }
-                vote_for!(&self, true, msg.candidated_addr);
+                self.broadcast_heartbeat();
+                self.maybe_send_timeout_now(true);
+            }
+        }
+    }
+
+    // Called on every heartbeat. Once per election timeout, step down unless
+    // a majority responded since the previous check. Returns false if we
+    // stepped down.
+    fn check_quorum(&mut self) -> bool {
+        self.quorum_check_elapsed += self.config.heartbeat_interval as u64;
+        if self.quorum_check_elapsed < ELECTION_TIMEOUT_MAX {
+            return true;
+        }
+        self.quorum_check_elapsed = 0;
+        let mut active: HashSet<NodeId> = self
+            .progress
+            .iter_mut()
+            .filter_map(|(id, progress)| {
+                std::mem::replace(&mut progress.recent_active, false).then_some(*id)
+            })
+            .collect();
+        active.insert(self.id);
+        if self.membership.has_quorum(|id| active.contains(id)) {
+            return true;
+        }
+        info!(
+            "{} lost contact with a majority of the cluster",
+            self.id
+        );
+        self.leader_id = None;
+        self.become_follower(self.current_term);
+        false
+    }
+
+    // Ask the other nodes whether we could win an election before starting
+    // it, so that our term only grows if we can
+    fn start_pre_vote(&mut self) {
+        self.change_role_to(Role::PreCandidate);
+        self.timer.run_elect();
+        self.leader_id = None;
+        info!(
+            "{} is pre-candidate for term {}",
+            self.id, self.current_term + 1
+        );
+        self.votes = HashSet::from([self.id]);
+        if self.membership.has_quorum(|id| self.votes.contains(id)) {
+            // single node cluster
+            self.start_election(false);
+            return;
+        }
+        pre_vote!(&self);
+    }
+
+    // Campaign in the next term. `leadership_transfer` is set when the leader
+    // told us to, so that voters stop following it right away.
+    fn start_election(&mut self, leadership_transfer: bool) {
+        self.timer.run_elect();
+        // vote for ourselves in the new term
+        if !self.set_term_and_vote(self.current_term + 1, Some(self.id)) {
+            return;
+        }
+        self.change_role_to(Role::Candidate);
+        info!("{} is candidate in term {}", self.id, self.current_term);
+        self.votes = HashSet::from([self.id]);
+        if self.membership.has_quorum(|id| self.votes.contains(id)) {
+            // single node cluster
+            self.become_leader();
+            return;
+        }
+        request_vote!(&self, leadership_transfer);
+    }
+
+    pub(crate) fn become_leader(&mut self) {
+        self.change_role_to(Role::Leader);
+        self.leader_id = Some(self.id);
+        self.quorum_check_elapsed = 0;
+        info!("{} is leader in term {}", self.id, self.current_term);
+        let next_index = self.storage.last_index() + 1;
+        for progress in self.progress.values_mut() {
+            *progress = Progress::new(next_index);
+        }
+        // Entries of earlier terms only commit along with one of this term,
+        // so start the term with an empty entry instead of waiting for a client
+        let no_op = Entry {
+            index: next_index,
+            term: self.current_term,
+            kind: EntryKind::NoOp,
+            command: Vec::new(),
+        };
+        if let Err(error) = self.append_entries(vec![no_op]) {
+            error!(
+                "{} failed to persist no-op entry: {}",
+                self.id, error
+            );
+        }
+        self.timer.run_heartbeat();
+        self.broadcast_heartbeat();
+        self.advance_commit_index();
+    }
+
+    // Step down to follower in `term`, which is at least the current one.
+    // Returns false if the new term could not be persisted.
+    fn become_follower(&mut self, term: u32) -> bool {
+        if term > self.current_term && !self.set_term_and_vote(term, None) {
+            return false;
+        }
+        if let Some(reply) = self.membership_change.take() {
+            // the next leader decides whether the change completes
+            let _ = reply.send(Err(ProposeError::NotLeader { leader_hint: None }));
+        }
+        if let Some(transfer) = &self.transfer {
+            // done once the target was told to take over
+            let result = if transfer.timeout_sent {
+                Ok(())
+            } else {
+                Err(TransferError::Aborted)
+            };
+            self.finish_transfer(result);
+        }
+        if !self.is_follower() {
+            info!(
+                "{} steps down to follower in term {}",
+                self.id, self.current_term
+            );
+            self.change_role_to(self.follower_role());
+            self.timer.run_elect();
+        }
+        true
+    }
+
+    fn is_follower(&self) -> bool {
+        matches!(self.role, Role::Follower | Role::Learner)
+    }
+
+    // The role of a node that is not leading or campaigning
+    fn follower_role(&self) -> Role {
+        if self.membership.is_learner(&self.id) {
+            Role::Learner
+        } else {
+            Role::Follower
+        }
+    }
+
+    fn handle_install_snapshot_request(&mut self, msg: InstallSnapshotRequest) {
+        if msg.term < self.current_term {
+            install_snapshot_response!(&self, msg.leader_id, msg.meta.last_included_index, 0, false);
+            return;
+        }
+        if (msg.term > self.current_term || !self.is_follower())
+            && !self.become_follower(msg.term)
+        {
+            return;
+        }
+        self.timer.reset_elect();
+        self.leader_id = Some(msg.leader_id);
+        self.leader_contact = Some(Instant::now());
+
+        let meta = msg.meta;
+        if msg.offset == 0 {
+            self.incoming_snapshot = Some(Snapshot {
+                meta: meta.clone(),
+                data: Vec::new(),
+            });
+        }
+        let received = match &self.incoming_snapshot {
+            Some(snapshot) if snapshot.meta == meta => snapshot.data.len(),
+            _ => 0,
+        };
+        if received != msg.offset {
+            // a chunk was lost or reordered, ask the leader to resend from `received`
+            install_snapshot_response!(&self, msg.leader_id, meta.last_included_index, received, false);
+            return;
+        }
+
+        let mut snapshot = self.incoming_snapshot.take().unwrap();
+        snapshot.data.extend_from_slice(&msg.data);
+        let received = snapshot.data.len();
+        if !msg.done {
+            self.incoming_snapshot = Some(snapshot);
+            install_snapshot_response!(&self, msg.leader_id, meta.last_included_index, received, false);
+            return;
+        }
+        if let Err(error) = self.install_snapshot(snapshot) {
+            error!(
+                "{} failed to install snapshot at {}: {}",
+                self.id, meta.last_included_index, error
+            );
+            return;
+        }
+        install_snapshot_response!(&self, msg.leader_id, meta.last_included_index, received, true);
+    }
+
+    fn handle_install_snapshot_response(&mut self, msg: InstallSnapshotResponse) {
+        if msg.term > self.current_term {
+            self.become_follower(msg.term);
+            return;
+        }
+        if self.role != Role::Leader || msg.term < self.current_term {
+            return;
+        }
+        let peer = msg.node_id;
+        let progress = match self.progress.get_mut(&peer) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.recent_active = true;
+        if progress.state != ProgressState::Snapshot {
+            return;
+        }
+
+        let snapshot = self.storage.snapshot();
+        if msg.last_included_index != snapshot.meta.last_included_index {
+            // we compacted again meanwhile, start over with the new snapshot
+            progress.become_snapshot(snapshot.meta.last_included_index);
+            install_snapshot_request!(&self, peer, snapshot, 0);
+        } else if msg.done {
+            progress.update(snapshot.meta.last_included_index);
+            progress.become_probe(snapshot.meta.last_included_index + 1);
+            info!(
+                "{} installed snapshot at {} on {}",
+                self.id, snapshot.meta.last_included_index, msg.node_id
+            );
+            self.advance_commit_index();
+            self.replicate_to(peer);
+        } else {
+            let offset = msg.offset.min(snapshot.data.len());
+            progress.snapshot_offset = offset;
+            install_snapshot_request!(&self, peer, snapshot, offset);
+        }
+    }
+
+    // Replace the log and the application state with a snapshot received
+    // from the leader, unless we already applied past it
+    pub(crate) fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<(), Box<dyn Error>> {
+        let index = snapshot.meta.last_included_index;
+        if index <= self.last_applied {
+            return Ok(());
+        }
+        self.state_machine.restore(&snapshot.data)?;
+        let meta = snapshot.meta.clone();
+        self.storage.apply_snapshot(snapshot)?;
+        self.last_applied = index;
+        self.commit_index = self.commit_index.max(index);
+        // the snapshot does not tell whether our own entries made it in
+        let covered: Vec<usize> = self.pending.keys().filter(|&&i| i <= index).copied().collect();
+        for i in covered {
+            if let Some((_, done)) = self.pending.remove(&i) {
+                let _ = done.send(Err(ProposeError::OutcomeUnknown));
             }
-            Role::Leader => {}
         }
+        self.log_bytes = log_bytes(&self.storage)?;
+        self.save_hard_state();
+        self.reload_membership();
+        info!(
+            "{} installed snapshot at index {} term {}",
+            self.id, index, meta.last_included_term
+        );
+        Ok(())
     }
     
-    fn handle_request_vote_response(&mut self, msg: RequestVoteResponse) {
-        match self.role {
-            Role::Follower => {}
-            Role::Candidate => {
-                if msg.term > self.current_term {
-                    self.current_term = msg.term;
-                    self.change_role_to(Role::Follower);
-                    self.timer.run_elect();
-                } else if msg.vote_granted {
-                    self.votes += 1;
-                    info!("{} gets {} votes",self.rpc.cs.socket_addr.port(), self.votes);
-                    if self.votes >= self.cluster_info.majority_number {
-                        self.change_role_to(Role::Leader);
-                        info!("{} is leader in term {}", self.rpc.cs.socket_addr.port(), self.current_term);
-                        self.timer.run_heartbeat();
-                        append_entries_request!(&self, Vec::<Entry>::new()); // heartbeat
-                    }
+    // Persist `entries` to storage. Entries already present with the same
+    // term are skipped, the first conflicting one truncates the existing
+    // suffix before the rest is appended.
+    fn append_entries(&mut self, entries: Vec<Entry>) -> Result<(), Box<dyn Error>> {
+        let mut new_entries = Vec::new();
+        let mut truncated = false;
+        for entry in entries {
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+            if entry.index < self.storage.first_index() {
+                // already covered by the snapshot
+                continue;
+            }
+            match self.storage.term(entry.index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    self.storage.truncate_suffix(entry.index)?;
+                    self.log_bytes = log_bytes(&self.storage)?;
+                    truncated = entry.index <= self.membership_index;
+                    new_entries.push(entry);
                 }
+                None => new_entries.push(entry),
+            }
+        }
+        self.storage.append(&new_entries)?;
+        self.log_bytes += new_entries.iter().map(Entry::size).sum::<usize>();
+
+        // a membership takes effect as soon as it is in the log, committed or not
+        if truncated {
+            self.reload_membership();
+        }
+        if let Some(entry) = new_entries
+            .iter()
+            .rev()
+            .find(|entry| entry.kind == EntryKind::ConfigChange)
+        {
+            match decode_record(&entry.command) {
+                Ok(membership) => self.set_membership(membership, entry.index),
+                Err(error) => error!(
+                    "{} ignored malformed membership at {}: {}",
+                    self.id, entry.index, error
+                ),
             }
-            Role::Leader => {}
         }
+        Ok(())
     }
 
-    fn handle_timeout(&mut self) {
-        match self.role {
-            Role::Follower => {
-                self.change_role_to(Role::Candidate);
-                self.timer.reset_elect();
-                self.current_term += 1;
-                info!("{} is candidate in term {}", self.rpc.cs.socket_addr.port(), self.current_term);
-                self.candidated_addr = Some(self.rpc.cs.socket_addr);
-                self.votes = 1;
-                request_vote!(&self);
-            }
-            Role::Candidate => {
-                self.timer.run_elect();
+    // The last membership logged at or before `index` and the index of its
+    // entry, falling back to the one in the snapshot and then to bootstrap
+    fn membership_at(&self, index: usize) -> (Membership, usize) {
+        let first_index = self.storage.first_index();
+        let last_index = index.min(self.storage.last_index());
+        if last_index >= first_index {
+            let entries = self.storage.entries(first_index, last_index + 1).unwrap_or_default();
+            for entry in entries.iter().rev() {
+                if entry.kind != EntryKind::ConfigChange {
+                    continue;
+                }
+                if let Ok(membership) = decode_record(&entry.command) {
+                    return (membership, entry.index);
+                }
             }
-            Role::Leader => {
-                append_entries_request!(&self, Vec::<Entry>::new()); // heartbeat
+        }
+        let meta = self.storage.snapshot().meta;
+        if !meta.membership.voters.is_empty() {
+            return (meta.membership, meta.last_included_index);
+        }
+        (self.bootstrap.clone(), 0)
+    }
+
+    // Recompute the membership from storage, e.g. after the log changed
+    fn reload_membership(&mut self) {
+        let (membership, index) = self.membership_at(self.storage.last_index());
+        self.set_membership(membership, index);
+    }
+
+    // Switch to `membership`, replicating to new members. Removed members
+    // keep receiving the log until the new membership is committed, so they
+    // learn they were removed instead of campaigning.
+    pub(crate) fn set_membership(&mut self, membership: Membership, index: usize) {
+        let members = membership.members();
+        let self_id = self.id;
+        let next_index = self.storage.last_index() + 1;
+        let previous = self.membership.members();
+        let committed = index <= self.commit_index;
+        self.progress
+            .retain(|id, _| members.contains(id) || (!committed && previous.contains(id)));
+        for id in &members {
+            if *id != self_id && !self.progress.contains_key(id) {
+                self.progress.insert(*id, Progress::new(next_index));
             }
         }
+        self.peers = members.iter().filter(|id| **id != self_id).copied().collect();
+        if membership != self.membership {
+            info!(
+                "{} membership at {}: {:?}",
+                self.id, index, membership
+            );
+        }
+        self.membership = membership;
+        self.membership_index = index;
+        if let Some(target) = self.transfer.as_ref().map(|transfer| transfer.target) {
+            if !self.membership.is_voter(&target) {
+                // the target was removed while catching up
+                self.finish_transfer(Err(TransferError::UnknownTarget(target)));
+            }
+        }
+        if self.is_follower() {
+            // added as a learner or promoted
+            self.change_role_to(self.follower_role());
+        }
     }
-    
+
+    // Start a membership change. Changing voters logs the joint membership
+    // first, the final one follows once it is committed.
+    fn handle_change_membership(
+        &mut self,
+        change: MembershipChange,
+        reply: Sender<Result<(), ProposeError>>,
+    ) {
+        if self.transfer.is_some() {
+            let _ = reply.send(Err(ProposeError::TransferringLeadership));
+            return;
+        }
+        if self.role != Role::Leader {
+            let _ = reply.send(Err(ProposeError::NotLeader {
+                leader_hint: self.leader_id,
+            }));
+            return;
+        }
+        if self.membership.is_joint()
+            || self.membership_index > self.commit_index
+            || self.membership_change.is_some()
+        {
+            let _ = reply.send(Err(ProposeError::MembershipChangeInProgress));
+            return;
+        }
+        let membership = match change {
+            MembershipChange::Voters(voters) => {
+                if voters.is_empty() || voters == self.membership.voters {
+                    let _ = reply.send(Ok(()));
+                    return;
+                }
+                self.membership.enter_joint(voters)
+            }
+            MembershipChange::AddLearner(learner) => {
+                if self.membership.members().contains(&learner) {
+                    let _ = reply.send(Ok(()));
+                    return;
+                }
+                self.membership.add_learner(learner)
+            }
+            MembershipChange::PromoteLearner(learner) => {
+                if !self.membership.is_learner(&learner) {
+                    let _ = reply.send(Err(ProposeError::NotLearner(learner)));
+                    return;
+                }
+                if self.progress[&learner].match_index < self.commit_index {
+                    let _ = reply.send(Err(ProposeError::LearnerBehind(learner)));
+                    return;
+                }
+                let mut voters = self.membership.voters.clone();
+                voters.insert(learner);
+                self.membership.enter_joint(voters)
+            }
+        };
+        if let Err(error) = self.append_membership(&membership) {
+            error!(
+                "{} failed to persist membership: {}",
+                self.id, error
+            );
+            let _ = reply.send(Err(ProposeError::StorageFailure));
+            return;
+        }
+        self.membership_change = Some(reply);
+    }
+
+    fn append_membership(&mut self, membership: &Membership) -> Result<(), Box<dyn Error>> {
+        let entry = Entry {
+            index: self.storage.last_index() + 1,
+            term: self.current_term,
+            kind: EntryKind::ConfigChange,
+            command: encode_record(membership)?,
+        };
+        self.append_entries(vec![entry])?;
+        self.replicate();
+        self.advance_commit_index();
+        Ok(())
+    }
+
+    // Drive a membership change on the leader: once the joint membership is
+    // committed log the final one, once that one is committed report the
+    // change done and step down if we are not part of the cluster anymore
+    fn maybe_finish_membership_change(&mut self) {
+        if self.role != Role::Leader || self.membership_index > self.commit_index {
+            return;
+        }
+        let members = self.membership.members();
+        self.progress.retain(|id, _| members.contains(id));
+        if self.membership.is_joint() {
+            let membership = self.membership.leave_joint();
+            if let Err(error) = self.append_membership(&membership) {
+                error!(
+                    "{} failed to persist membership: {}",
+                    self.id, error
+                );
+            }
+            return;
+        }
+        if let Some(reply) = self.membership_change.take() {
+            let _ = reply.send(Ok(()));
+        }
+        if !self.membership.is_voter(&self.id) {
+            info!("{} removed from the cluster", self.id);
+            self.leader_id = None;
+            self.become_follower(self.current_term);
+        }
+    }
+
+    // Feed the entries committed since the last call to the state machine
+    fn apply_committed(&mut self) {
+        if self.last_applied >= self.commit_index {
+            return;
+        }
+        let entries = match self.storage.entries(self.last_applied + 1, self.commit_index + 1) {
+            Ok(entries) => entries,
+            Err(error) => {
+                error!(
+                    "{} failed to read committed entries: {}",
+                    self.id, error
+                );
+                return;
+            }
+        };
+        for entry in entries {
+            let output = match entry.kind {
+                EntryKind::Normal => Some(self.state_machine.apply(&entry)),
+                EntryKind::NoOp | EntryKind::ConfigChange => None,
+            };
+            self.last_applied = entry.index;
+            if let Some((term, done)) = self.pending.remove(&entry.index) {
+                // another leader replaced our entry at this index
+                let result = match output {
+                    Some(output) if term == entry.term => Ok(output),
+                    _ => Err(ProposeError::Dropped),
+                };
+                let _ = done.send(result);
+            }
+        }
+    }
+
+    // Compact the log once it exceeds the configured entry count or size
+    fn maybe_snapshot(&mut self) {
+        let log_entries = self.storage.last_index() + 1 - self.storage.first_index();
+        if log_entries < self.config.snapshot_max_entries
+            && self.log_bytes < self.config.snapshot_max_bytes
+        {
+            return;
+        }
+        if self.last_applied < self.storage.first_index() {
+            // nothing applied since the last snapshot
+            return;
+        }
+        if let Err(error) = self.take_snapshot() {
+            error!(
+                "{} failed to take snapshot at {}: {}",
+                self.id, self.last_applied, error
+            );
+        }
+    }
+
+    // Replace the log up to last_applied with a snapshot of the applied state
+    fn take_snapshot(&mut self) -> Result<(), Box<dyn Error>> {
+        let index = self.last_applied;
+        let term = match self.storage.term(index) {
+            Some(term) => term,
+            None => return Ok(()),
+        };
+        let data = self.state_machine.snapshot();
+        self.storage.apply_snapshot(Snapshot {
+            meta: SnapshotMeta {
+                last_included_index: index,
+                last_included_term: term,
+                membership: self.membership_at(index).0,
+            },
+            data,
+        })?;
+        self.log_bytes = log_bytes(&self.storage)?;
+        info!(
+            "{} took snapshot at index {} term {}",
+            self.id, index, term
+        );
+        Ok(())
+    }
+
+    // Move to `term` with `voted_for` as the vote cast in it. The change is
+    // written to disk first and only takes effect if that succeeds.
+    fn set_term_and_vote(&mut self, term: u32, voted_for: Option<NodeId>) -> bool {
+        let state = HardState {
+            term,
+            voted_for,
+            commit_index: self.commit_index,
+        };
+        if !self.write_hard_state(&state) {
+            return false;
+        }
+        self.current_term = term;
+        self.candidate_id = voted_for;
+        true
+    }
+
+    // Write term, vote and commit index to disk
+    fn save_hard_state(&mut self) -> bool {
+        let state = HardState {
+            term: self.current_term,
+            voted_for: self.candidate_id,
+            commit_index: self.commit_index,
+        };
+        self.write_hard_state(&state)
+    }
+
+    fn write_hard_state(&mut self, state: &HardState) -> bool {
+        match self.storage.set_hard_state(state) {
+            Ok(()) => true,
+            Err(error) => {
+                error!(
+                    "{} failed to persist hard state: {}",
+                    self.id, error
+                );
+                false
+            }
+        }
+    }
+
     fn change_role_to(&mut self, rolename: Role) {
         self.role = rolename;
     }
 
+    pub fn status(&self) -> Status {
+        Status {
+            id: self.id,
+            addr: self.rpc.transport.local_addr(),
+            role: self.role,
+            term: self.current_term,
+            leader: self.leader_id,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            membership: self.membership.clone(),
+        }
+    }
+
     pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
         // RPC Server/Client Thread
         self.start_rpc_listener()?;
+        let _stop = StopGuard {
+            id: self.id,
+            timer: self.timer.clone(),
+            transport: Arc::clone(&self.rpc.transport),
+            listener: self.rpc.listener.take(),
+        };
 
         // Main Thread
-        self.start_raft_server()?;
+        self.start_raft_server()
+    }
 
-        Ok(())
+    // Run the node in a background thread, controlled by the returned handle
+    pub fn start(mut self) -> Result<NodeHandle<M::Output>, Box<dyn Error>>
+    where
+        S: Send + 'static,
+        M: Send + 'static,
+        M::Output: Send + 'static,
+    {
+        let proposer = self.proposer();
+        let control = self.control_sender.clone();
+        let transport: Arc<dyn Transport> = self.rpc.transport.clone();
+        let id = self.id;
+        let thread = thread::Builder::new()
+            .name(format!("ruft-{}", id))
+            .spawn(move || {
+                if let Err(error) = self.run() {
+                    error!("{} Raft loop error: {}", id, error);
+                }
+            })?;
+        Ok(NodeHandle::new(proposer, control, transport, thread))
     }
 }
+
+// Stops the timer threads and the RPC listener once the Raft loop exits,
+// also when it panics. The socket is closed once the listener exits.
+struct StopGuard<T: Transport> {
+    id: NodeId,
+    timer: NodeTimer,
+    transport: Arc<T>,
+    listener: Option<JoinHandle<Result<(), InitializationError>>>,
+}
+
+impl<T: Transport> Drop for StopGuard<T> {
+    fn drop(&mut self) {
+        self.timer.stop();
+        self.transport.stop();
+        if let Some(listener) = self.listener.take() {
+            if listener.join().is_err() {
+                error!("{} RPC listener panicked", self.id);
+            }
+        }
+    }
+}
+
+// Size of the entries currently in the log
+fn log_bytes<S: Storage>(storage: &S) -> Result<usize, Box<dyn Error>> {
+    let entries = storage.entries(storage.first_index(), storage.last_index() + 1)?;
+    Ok(entries.iter().map(Entry::size).sum())
+}
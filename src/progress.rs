@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+// How the leader replicates to a follower
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ProgressState {
+    // Where the follower's log matches is unknown, send one AppendEntries at
+    // a time until one is accepted
+    Probe,
+    // The follower is in sync, stream entries without waiting for responses
+    // as long as the in-flight window has room
+    Replicate,
+    // The follower needs compacted entries and is being sent the snapshot
+    Snapshot,
+}
+
+// A message sent to the follower and not acknowledged yet
+struct InFlight {
+    last_index: usize,
+    bytes: usize,
+    sent_at: usize, // tick count when it was sent
+}
+
+// The leader's view of one follower
+pub struct Progress {
+    pub state: ProgressState,
+    pub next_index: usize,
+    pub match_index: usize,
+    pub snapshot_index: usize,  // last index of the snapshot being sent
+    pub snapshot_offset: usize, // bytes of that snapshot acknowledged so far
+    pub recent_active: bool,    // responded since the last quorum check
+    probe_sent: bool,           // waiting for the response to a probe
+    in_flight: VecDeque<InFlight>,
+    in_flight_bytes: usize,
+    ticks: usize,
+}
+
+impl Progress {
+    pub fn new(next_index: usize) -> Progress {
+        Progress {
+            state: ProgressState::Probe,
+            next_index,
+            match_index: 0,
+            snapshot_index: 0,
+            snapshot_offset: 0,
+            recent_active: false,
+            probe_sent: false,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            ticks: 0,
+        }
+    }
+
+    pub fn become_probe(&mut self, next_index: usize) {
+        self.reset(ProgressState::Probe);
+        self.next_index = next_index.max(self.match_index + 1);
+    }
+
+    pub fn become_replicate(&mut self) {
+        self.reset(ProgressState::Replicate);
+        self.next_index = self.match_index + 1;
+    }
+
+    // Start sending the snapshot ending at `snapshot_index` from its start
+    pub fn become_snapshot(&mut self, snapshot_index: usize) {
+        self.reset(ProgressState::Snapshot);
+        self.snapshot_index = snapshot_index;
+        self.snapshot_offset = 0;
+    }
+
+    fn reset(&mut self, state: ProgressState) {
+        self.state = state;
+        self.probe_sent = false;
+        self.in_flight.clear();
+        self.in_flight_bytes = 0;
+    }
+
+    // Whether the leader has to wait before sending more entries
+    pub fn is_paused(&self, max_msgs: usize, max_bytes: usize) -> bool {
+        match self.state {
+            ProgressState::Probe => self.probe_sent,
+            ProgressState::Replicate => {
+                self.in_flight.len() >= max_msgs || self.in_flight_bytes >= max_bytes
+            }
+            ProgressState::Snapshot => true,
+        }
+    }
+
+    // Record an AppendEntriesRequest carrying entries up to `last_index`
+    pub fn sent(&mut self, last_index: usize, bytes: usize) {
+        match self.state {
+            ProgressState::Probe => self.probe_sent = true,
+            ProgressState::Replicate => {
+                self.next_index = last_index + 1;
+                self.in_flight.push_back(InFlight {
+                    last_index,
+                    bytes,
+                    sent_at: self.ticks,
+                });
+                self.in_flight_bytes += bytes;
+            }
+            ProgressState::Snapshot => {}
+        }
+    }
+
+    // The follower stored everything up to `match_index`. Returns false if
+    // the acknowledgement is older than one already received.
+    pub fn update(&mut self, match_index: usize) -> bool {
+        if match_index < self.match_index {
+            return false;
+        }
+        self.match_index = match_index;
+        self.next_index = self.next_index.max(match_index + 1);
+        while let Some(message) = self.in_flight.front() {
+            if message.last_index > match_index {
+                break;
+            }
+            self.in_flight_bytes -= message.bytes;
+            self.in_flight.pop_front();
+        }
+        true
+    }
+
+    // Called on every heartbeat. Probes are resent, and a follower whose
+    // oldest in-flight message went unacknowledged for a whole interval is
+    // probed again since datagrams may have been lost.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        match self.state {
+            ProgressState::Probe => self.probe_sent = false,
+            ProgressState::Replicate => {
+                if self
+                    .in_flight
+                    .front()
+                    .is_some_and(|message| message.sent_at + 2 <= self.ticks)
+                {
+                    self.become_probe(self.match_index + 1);
+                }
+            }
+            ProgressState::Snapshot => {}
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::error::ProposeError;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+// Resolves a proposal with the output of the state machine
+pub type Completion<T> = Sender<Result<T, ProposeError>>;
+
+// A command submitted to the Raft loop, answered on `reply` once appended to
+// the leader's log and on `done` once applied
+pub struct ProposalRequest<T> {
+    pub command: Vec<u8>,
+    pub reply: Sender<Result<(usize, u32), ProposeError>>,
+    pub done: Completion<T>,
+}
+
+// Submits commands to a Node, can be cloned and moved to other threads
+pub struct Proposer<T> {
+    sender: Sender<ProposalRequest<T>>,
+}
+
+impl<T> Clone for Proposer<T> {
+    fn clone(&self) -> Self {
+        Proposer {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> Proposer<T> {
+    pub fn new(sender: Sender<ProposalRequest<T>>) -> Proposer<T> {
+        Proposer { sender }
+    }
+
+    // Append `command` to the leader's log. Fails with NotLeader on any other
+    // node, otherwise returns once the entry is in the leader's log.
+    pub fn propose(&self, command: impl Into<Vec<u8>>) -> Result<Proposal<T>, ProposeError> {
+        let (reply, reply_receiver) = bounded(1);
+        let (done, receiver) = bounded(1);
+        self.sender
+            .send(ProposalRequest {
+                command: command.into(),
+                reply,
+                done,
+            })
+            .map_err(|_| ProposeError::Stopped)?;
+        let (index, term) = reply_receiver.recv().map_err(|_| ProposeError::Stopped)??;
+        Ok(Proposal {
+            index,
+            term,
+            receiver,
+        })
+    }
+}
+
+// A command appended to the leader's log at `index` in `term`
+pub struct Proposal<T> {
+    pub index: usize,
+    pub term: u32,
+    receiver: Receiver<Result<T, ProposeError>>,
+}
+
+impl<T> Proposal<T> {
+    // Block until the entry is committed and applied, returning the output of
+    // the state machine
+    pub fn wait(self) -> Result<T, ProposeError> {
+        self.receiver.recv().map_err(|_| ProposeError::Stopped)?
+    }
+
+    // Non-blocking version of wait, None while the entry is not applied yet
+    pub fn try_wait(&self) -> Option<Result<T, ProposeError>> {
+        self.receiver.try_recv().ok()
+    }
+}
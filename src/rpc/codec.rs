@@ -0,0 +1,61 @@
+use super::RPCMessage;
+use crate::error::CodecError;
+
+use bincode::Options;
+use serde::Serialize;
+use std::error::Error;
+
+// First byte of a message in version 1 of the binary format. JSON messages
+// start with '{', so both can be told apart on receipt.
+const BINARY_V1: u8 = 1;
+
+// How a transport encodes the messages it sends. Every format is accepted
+// on receipt, a cluster can switch without downtime.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Codec {
+    // Compact bincode encoding after a version byte
+    #[default]
+    Binary,
+    // Human readable, for debugging
+    Json,
+}
+
+impl Codec {
+    pub fn encode(self, message: &RPCMessage) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Codec::Binary => {
+                let mut bytes = vec![BINARY_V1];
+                bincode_options(usize::MAX).serialize_into(&mut bytes, message)?;
+                Ok(bytes)
+            }
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+        }
+    }
+
+    // Bytes `value`, e.g. an entry, takes in a message encoded with this
+    // codec
+    pub fn encoded_size<T: Serialize>(self, value: &T) -> usize {
+        match self {
+            Codec::Binary => bincode_options(usize::MAX)
+                .serialized_size(value)
+                .map_or(0, |size| size as usize),
+            Codec::Json => serde_json::to_vec(value).map_or(0, |encoded| encoded.len()),
+        }
+    }
+
+    // Decode a message in any of the formats, whatever codec this node
+    // sends with
+    pub fn decode(bytes: &[u8]) -> Result<RPCMessage, Box<dyn Error>> {
+        match bytes.first() {
+            // nothing in a message is larger than the message itself
+            Some(&BINARY_V1) => Ok(bincode_options(bytes.len()).deserialize(&bytes[1..])?),
+            Some(b'{') => Ok(serde_json::from_slice(bytes)?),
+            Some(format) => Err(Box::new(CodecError::UnknownFormat(*format))),
+            None => Err(Box::new(CodecError::Empty)),
+        }
+    }
+}
+
+fn bincode_options(limit: usize) -> impl Options {
+    bincode::DefaultOptions::new().with_limit(limit as u64)
+}
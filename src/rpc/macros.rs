@@ -1,68 +1,158 @@
+// Sending is best effort: a message the transport fails to send is logged
+// and dropped like a lost one, Raft retries what matters
+#[macro_export]
+macro_rules! send_message {
+    ($node:expr, $peer: expr, $message: expr) => {
+        if let Err(error) = $node.rpc.transport.send_to($peer, $message) {
+            log::error!("{} failed to send to {}: {}", $node.id, $peer, error);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! broadcast_message {
+    ($node:expr, $message: expr) => {
+        if let Err(error) = $node.rpc.transport.broadcast(&$node.peers, $message) {
+            log::error!("{} failed to broadcast: {}", $node.id, error);
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! append_entries_request {
-    //parameter:&self, entries:Vec<String>
-    ($node:expr, $entries: expr) => {
-        let aer_msg = RPCMessage::new(Message::AppendEntriesRequest(AppendEntriesRequest::new(
+    //parameter:&self, peer:NodeId, prev_log_index:usize, prev_log_term:u32, entries:Vec<Entry>
+    ($node:expr, $peer: expr, $prev_log_index: expr, $prev_log_term: expr, $entries: expr) => {
+        let aer_msg = Message::AppendEntriesRequest(AppendEntriesRequest::new(
             $node.current_term,
-            $node.rpc.cs.socket_addr,
-            $node.last_applied as usize,
-            $node.logs.last().unwrap().term,
+            $node.id,
+            $prev_log_index,
+            $prev_log_term,
             $entries,
             $node.commit_index,
-        )))
-        .unwrap();
-        $node.rpc.cs.send_all(&aer_msg).unwrap();
+        ));
+        send_message!($node, $peer, aer_msg);
     };
 }
 
 #[macro_export]
 macro_rules! append_entries_response {
-    //parameter:&self, success:bool, leader:SocketAddr
-    ($node:expr, $success: expr, $leader: expr) => {
-        let aer_msg = RPCMessage::new(Message::AppendEntriesResponse(AppendEntriesResponse::new(
-            $node.rpc.cs.socket_addr,
-            $node.logs.last().unwrap().index + 1,
-            $node.logs.last().unwrap().index + 1,
+    //parameter:&self, success:bool, leader:NodeId, match_index:usize, conflict:(Option<u32>, usize)
+    ($node:expr, $success: expr, $leader: expr, $match_index: expr, $conflict: expr) => {
+        let (conflict_term, conflict_index) = $conflict;
+        let next_index = if $success {
+            $match_index + 1
+        } else {
+            conflict_index
+        };
+        let aer_msg = Message::AppendEntriesResponse(AppendEntriesResponse::new(
+            $node.id,
+            next_index,
+            $match_index,
             $node.current_term,
             $success,
-        )))
-        .unwrap();
-        $node
-            .rpc
-            .cs
-            .send_to($leader, &aer_msg)
-            .unwrap();
+            conflict_term,
+            conflict_index,
+        ));
+        send_message!($node, $leader, aer_msg);
     };
 }
 
 #[macro_export]
 macro_rules! request_vote {
-    //parameter:&self (send to all)
-    ($node:expr) => {
-        let rvr_msg = RPCMessage::new(Message::RequestVoteRequest(RequestVoteRequest::new(
+    //parameter:&self, leadership_transfer:bool (send to all)
+    ($node:expr, $leadership_transfer: expr) => {
+        let mut request = RequestVoteRequest::new(
             $node.current_term,
-            $node.rpc.cs.socket_addr,
-            $node.last_applied as usize,
-            $node.logs.last().unwrap().term,
-        )))
-        .unwrap();
-        $node.rpc.cs.send_all(&rvr_msg).unwrap();
+            $node.id,
+            $node.storage.last_index(),
+            $node.storage.last_term(),
+        );
+        request.leadership_transfer = $leadership_transfer;
+        let rvr_msg = Message::RequestVoteRequest(request);
+        broadcast_message!($node, rvr_msg);
     };
 }
 
 #[macro_export]
 macro_rules! vote_for {
-    //parameter:&self, success:bool, candidate: SocketAddr
+    //parameter:&self, success:bool, candidate: NodeId
     ($node:expr, $vote_granted: expr, $candidate: expr) => {
-        let rvr_msg = RPCMessage::new(Message::RequestVoteResponse(RequestVoteResponse::new(
+        let rvr_msg = Message::RequestVoteResponse(RequestVoteResponse::new(
+            $node.id,
             $node.current_term,
             $vote_granted,
-        )))
-        .unwrap();
-        $node
-            .rpc
-            .cs
-            .send_to($candidate, &rvr_msg)
-            .unwrap();
+        ));
+        send_message!($node, $candidate, rvr_msg);
+    };
+}
+
+#[macro_export]
+macro_rules! pre_vote {
+    //parameter:&self (send to all)
+    ($node:expr) => {
+        let pvr_msg = Message::PreVoteRequest(PreVoteRequest::new(
+            $node.current_term + 1,
+            $node.id,
+            $node.storage.last_index(),
+            $node.storage.last_term(),
+        ));
+        broadcast_message!($node, pvr_msg);
+    };
+}
+
+#[macro_export]
+macro_rules! pre_vote_for {
+    //parameter:&self, term:u32, success:bool, candidate: NodeId
+    ($node:expr, $term: expr, $vote_granted: expr, $candidate: expr) => {
+        let pvr_msg = Message::PreVoteResponse(PreVoteResponse::new(
+            $node.id,
+            $term,
+            $vote_granted,
+        ));
+        send_message!($node, $candidate, pvr_msg);
+    };
+}
+
+#[macro_export]
+macro_rules! timeout_now {
+    //parameter:&self, peer:NodeId
+    ($node:expr, $peer: expr) => {
+        let tn_msg = Message::TimeoutNow(TimeoutNow::new(
+            $node.current_term,
+            $node.id,
+        ));
+        send_message!($node, $peer, tn_msg);
+    };
+}
+
+#[macro_export]
+macro_rules! install_snapshot_request {
+    //parameter:&self, peer:NodeId, snapshot:&Snapshot, offset:usize
+    ($node:expr, $peer: expr, $snapshot: expr, $offset: expr) => {
+        let end = $node.snapshot_chunk_end(&$snapshot.data, $offset);
+        let isr_msg = Message::InstallSnapshotRequest(InstallSnapshotRequest::new(
+            $node.current_term,
+            $node.id,
+            $snapshot.meta.clone(),
+            $offset,
+            $snapshot.data[$offset..end].to_vec(),
+            end == $snapshot.data.len(),
+        ));
+        send_message!($node, $peer, isr_msg);
+    };
+}
+
+#[macro_export]
+macro_rules! install_snapshot_response {
+    //parameter:&self, leader:NodeId, last_included_index:usize, offset:usize, done:bool
+    ($node:expr, $leader: expr, $last_included_index: expr, $offset: expr, $done: expr) => {
+        let isr_msg = Message::InstallSnapshotResponse(InstallSnapshotResponse::new(
+            $node.id,
+            $node.current_term,
+            $last_included_index,
+            $offset,
+            $done,
+        ));
+        send_message!($node, $leader, isr_msg);
     };
 }
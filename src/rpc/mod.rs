@@ -1,28 +1,42 @@
 #[macro_use]
 pub mod macros;
+mod codec;
+mod tcp;
+mod udp;
 
+pub use codec::Codec;
+pub use tcp::TcpTransport;
+pub use udp::UdpTransport;
+
+use crate::address_book::NodeId;
 use crate::entry::Entry;
+use crate::error::InitializationError;
+use crate::storage::SnapshotMeta;
 
 use crossbeam_channel::{Sender, Receiver};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use log::error;
 use std::error::Error;
-use std::net::{SocketAddr, UdpSocket, ToSocketAddrs};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::thread::JoinHandle;
 
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub enum Message {
     AppendEntriesRequest(AppendEntriesRequest),
     AppendEntriesResponse(AppendEntriesResponse),
     RequestVoteRequest(RequestVoteRequest),
     RequestVoteResponse(RequestVoteResponse),
+    PreVoteRequest(PreVoteRequest),
+    PreVoteResponse(PreVoteResponse),
+    TimeoutNow(TimeoutNow),
+    InstallSnapshotRequest(InstallSnapshotRequest),
+    InstallSnapshotResponse(InstallSnapshotResponse),
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct AppendEntriesRequest {
     pub term: u32,
-    pub leader_addr: SocketAddr,
+    pub leader_id: NodeId,
     pub prev_log_index: usize,
     pub prev_log_term: u32,
     pub entries: Vec<Entry>,
@@ -32,7 +46,7 @@ pub struct AppendEntriesRequest {
 impl AppendEntriesRequest {
     pub fn new(
         term: u32,
-        leader_addr: SocketAddr,
+        leader_id: NodeId,
         prev_log_index: usize,
         prev_log_term: u32,
         entries: Vec<Entry>,
@@ -40,7 +54,7 @@ impl AppendEntriesRequest {
     ) -> AppendEntriesRequest {
         AppendEntriesRequest {
             term,
-            leader_addr,
+            leader_id,
             prev_log_index,
             prev_log_term,
             entries,
@@ -49,76 +63,221 @@ impl AppendEntriesRequest {
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct AppendEntriesResponse {
-    pub socket_addr: SocketAddr,
+    pub node_id: NodeId,
     pub next_index: usize,
     pub match_index: usize,
     pub term: u32,
     pub success: bool,
+    // On rejection, the term of the follower's conflicting entry (None if its
+    // log is too short) and the first index it holds for that term
+    pub conflict_term: Option<u32>,
+    pub conflict_index: usize,
 }
 
 impl AppendEntriesResponse {
-    pub fn new(socket_addr: SocketAddr,
+    pub fn new(node_id: NodeId,
         next_index: usize,
         match_index: usize,
         term: u32,
-        success: bool
+        success: bool,
+        conflict_term: Option<u32>,
+        conflict_index: usize,
     ) -> AppendEntriesResponse {
         AppendEntriesResponse {
-            socket_addr,
+            node_id,
             next_index,
             match_index,
             term,
             success,
+            conflict_term,
+            conflict_index,
         }
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct RequestVoteRequest {
     pub term: u32,
-    pub candidated_addr: SocketAddr,
+    pub candidate_id: NodeId,
     pub last_log_index: usize,
     pub last_log_term: u32,
+    // The candidate was told to take over by the leader, voters do not wait
+    // for their last leader contact to be old enough
+    pub leadership_transfer: bool,
 }
 
 impl RequestVoteRequest {
     pub fn new(
         term: u32,
-        candidated_addr: SocketAddr,
+        candidate_id: NodeId,
         last_log_index: usize,
         last_log_term: u32,
     ) -> RequestVoteRequest {
         RequestVoteRequest {
             term,
-            candidated_addr,
+            candidate_id,
             last_log_index,
             last_log_term,
+            leadership_transfer: false,
         }
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct RequestVoteResponse {
+    pub node_id: NodeId,
     pub term: u32,
     pub vote_granted: bool,
 }
 
 impl RequestVoteResponse {
-    pub fn new(term: u32, vote_granted: bool) -> RequestVoteResponse {
-        RequestVoteResponse { term, vote_granted }
+    pub fn new(node_id: NodeId, term: u32, vote_granted: bool) -> RequestVoteResponse {
+        RequestVoteResponse {
+            node_id,
+            term,
+            vote_granted,
+        }
+    }
+}
+
+// Asks whether the sender could win an election in `term` before it starts
+// one, nothing changes on the receiver
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct PreVoteRequest {
+    pub term: u32,
+    pub candidate_id: NodeId,
+    pub last_log_index: usize,
+    pub last_log_term: u32,
+}
+
+impl PreVoteRequest {
+    pub fn new(
+        term: u32,
+        candidate_id: NodeId,
+        last_log_index: usize,
+        last_log_term: u32,
+    ) -> PreVoteRequest {
+        PreVoteRequest {
+            term,
+            candidate_id,
+            last_log_index,
+            last_log_term,
+        }
+    }
+}
+
+// `term` is the one of the request if the vote is granted, the current term
+// of the responder otherwise
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct PreVoteResponse {
+    pub node_id: NodeId,
+    pub term: u32,
+    pub vote_granted: bool,
+}
+
+impl PreVoteResponse {
+    pub fn new(node_id: NodeId, term: u32, vote_granted: bool) -> PreVoteResponse {
+        PreVoteResponse {
+            node_id,
+            term,
+            vote_granted,
+        }
+    }
+}
+
+// Sent by the leader to the target of a leadership transfer once its log is
+// up to date, the target starts an election right away
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct TimeoutNow {
+    pub term: u32,
+    pub leader_id: NodeId,
+}
+
+impl TimeoutNow {
+    pub fn new(term: u32, leader_id: NodeId) -> TimeoutNow {
+        TimeoutNow { term, leader_id }
+    }
+}
+
+// One chunk of the leader's snapshot, sent to followers whose next_index
+// falls behind the compacted prefix of the leader's log
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotRequest {
+    pub term: u32,
+    pub leader_id: NodeId,
+    pub meta: SnapshotMeta,
+    pub offset: usize,
+    pub data: Vec<u8>,
+    pub done: bool,
+}
+
+impl InstallSnapshotRequest {
+    pub fn new(
+        term: u32,
+        leader_id: NodeId,
+        meta: SnapshotMeta,
+        offset: usize,
+        data: Vec<u8>,
+        done: bool,
+    ) -> InstallSnapshotRequest {
+        InstallSnapshotRequest {
+            term,
+            leader_id,
+            meta,
+            offset,
+            data,
+            done,
+        }
+    }
+}
+
+// `offset` is the next byte the follower expects, the whole snapshot is
+// installed once `done` is set
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotResponse {
+    pub node_id: NodeId,
+    pub term: u32,
+    pub last_included_index: usize,
+    pub offset: usize,
+    pub done: bool,
+}
+
+impl InstallSnapshotResponse {
+    pub fn new(
+        node_id: NodeId,
+        term: u32,
+        last_included_index: usize,
+        offset: usize,
+        done: bool,
+    ) -> InstallSnapshotResponse {
+        InstallSnapshotResponse {
+            node_id,
+            term,
+            last_included_index,
+            offset,
+            done,
+        }
     }
 }
 
+// The envelope of every message sent over a socket, the sender's address
+// keeps the receiver's address book current when a node moves
 #[derive(PartialEq, Serialize, Deserialize, Debug)]
 pub struct RPCMessage {
+    pub from: NodeId,
+    pub from_addr: SocketAddr,
     pub message: Message,
 }
 
 impl RPCMessage {
-    pub fn new(message: Message) -> Result<RPCMessage, Box<dyn Error>> {
-        Ok(RPCMessage { message })
+    pub fn new(from: NodeId, from_addr: SocketAddr, message: Message) -> RPCMessage {
+        RPCMessage {
+            from,
+            from_addr,
+            message,
+        }
     }
 
     pub fn from_json(json_str: String) -> Result<RPCMessage, Box<dyn Error>> {
@@ -128,70 +287,46 @@ impl RPCMessage {
     }
 }
 
-pub struct RPCCS {
-    socket: UdpSocket,
-    pub socket_addr: SocketAddr,
-    peer_list: Vec<SocketAddr>,
-}
-
-// RPC Client & Server
-impl RPCCS {
-    pub fn new(
-        socket_addr: SocketAddr,
-        peer_list: Vec<SocketAddr>,
-    ) -> Result<RPCCS, Box<dyn Error>> {
-        let socket = UdpSocket::bind(socket_addr)?;
-        Ok(RPCCS {
-            socket,
-            socket_addr,
-            peer_list,
-        })
-    }
+// How a node exchanges messages with its peers. Delivery is best effort,
+// Raft copes with lost, duplicated and reordered messages.
+pub trait Transport: Send + Sync + 'static {
+    fn send_to(&self, peer: NodeId, message: Message) -> Result<(), Box<dyn Error>>;
 
-    pub fn start_listener(&self, rpc_notifier: Sender<RPCMessage>) -> Result<(), Box<dyn Error>> {
-        loop {
-            let mut buffer = [0; 1024];
-            let (amt, _) = match self.socket.recv_from(&mut buffer) {
-                Ok(pair) => pair,
-                Err(_) => {
-                    error!("{} receive error", self.socket_addr.port());
-                    (0, "127.0.0.1:8006".to_socket_addrs()?.next().unwrap())
-                }
-            };
-            if let Ok(msg_content) = String::from_utf8(buffer[..amt].to_vec()) {
-                // Handle the raw RPC request from socket buffer
-                let msg_parsed = RPCMessage::from_json(msg_content)?;
-                rpc_notifier.send(msg_parsed)?;
+    // A peer that cannot be sent to does not keep the others from receiving
+    // the message, the last error is returned once all were tried
+    fn broadcast(&self, peers: &[NodeId], message: Message) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for peer in peers {
+            if let Err(error) = self.send_to(*peer, message.clone()) {
+                result = Err(error);
             }
         }
+        result
     }
 
-    // Send request to one node in peer_list
-    pub fn send_to(
-        &self,
-        recv_node: SocketAddr,
-        message_to_send: &RPCMessage,
-    ) -> Result<(), Box<dyn Error>> {
-        //recv_node: host, port
-        let msg_parsed = json!(message_to_send).to_string();
-        let buffer = msg_parsed.as_bytes();
-        self.socket.send_to(&buffer, recv_node)?;
-        Ok(())
+    // Forward every message received from a peer to `incoming` until stop()
+    // is called, run on a thread of its own
+    fn listen(&self, incoming: Sender<Message>) -> Result<(), Box<dyn Error>>;
+
+    fn stop(&self);
+
+    // Largest encoded message the transport can deliver, None if it does not
+    // limit message sizes
+    fn max_message_size(&self) -> Option<usize> {
+        None
     }
 
-    // Send request to all nodes in peer_list, eg, heartbeat
-    pub fn send_all(&self, message_to_send: &RPCMessage) -> Result<(), Box<dyn Error>> {
-        for peer in &self.peer_list {
-            let msg_parsed = json!(message_to_send).to_string();
-            let buffer = msg_parsed.as_bytes();
-            self.socket.send_to(&buffer, peer)?;
-        }
-        Ok(())
+    // Where `peer` can be reached, for transports that use addresses
+    fn set_address(&self, _peer: NodeId, _addr: SocketAddr) {}
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
     }
 }
 
-pub struct Rpc {
-    pub cs: Arc<RPCCS>,
-    pub notifier: Option<Sender<RPCMessage>>,
-    pub receiver: Option<Receiver<RPCMessage>>,
+pub struct Rpc<T: Transport> {
+    pub transport: Arc<T>,
+    pub notifier: Option<Sender<Message>>,
+    pub receiver: Option<Receiver<Message>>,
+    pub listener: Option<JoinHandle<Result<(), InitializationError>>>,
 }
\ No newline at end of file
@@ -0,0 +1,309 @@
+use super::codec::Codec;
+use super::{Message, RPCMessage, Transport};
+use crate::address_book::{AddressBook, NodeId};
+use crate::error::CodecError;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often blocked threads check whether the transport was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+// Delay before reconnecting to a peer, doubled after each failed attempt
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(20);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+// Frames waiting for a peer's connection, newer ones are dropped when full
+const PEER_QUEUE_SIZE: usize = 1024;
+// Bytes of the big-endian length in front of every frame
+const FRAME_HEADER_SIZE: usize = 4;
+// Largest payload of a frame, a connection announcing more is dropped
+// instead of buffering it
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// Length-prefixed RPCMessages over one persistent connection per peer.
+// Frames to a peer are written by a thread of its own, so a slow or dead
+// peer never blocks the Raft loop.
+pub struct TcpTransport {
+    listener: TcpListener,
+    node_id: NodeId,
+    socket_addr: SocketAddr,
+    addresses: AddressBook,
+    codec: Codec,
+    peers: Mutex<HashMap<NodeId, Sender<Vec<u8>>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl TcpTransport {
+    pub fn bind(
+        node_id: NodeId,
+        socket_addr: SocketAddr,
+        addresses: AddressBook,
+        codec: Codec,
+    ) -> Result<TcpTransport, Box<dyn Error>> {
+        let listener = TcpListener::bind(socket_addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpTransport {
+            listener,
+            node_id,
+            socket_addr,
+            addresses,
+            codec,
+            peers: Mutex::new(HashMap::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn encode(&self, message: Message) -> Result<Vec<u8>, Box<dyn Error>> {
+        let payload = self.codec.encode(&RPCMessage::new(self.node_id, self.socket_addr, message))?;
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(Box::new(CodecError::TooLarge(payload.len())));
+        }
+        let length = u32::try_from(payload.len())?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    // Queue `frame` for `peer`, starting its writer on first use
+    fn enqueue(&self, peer: NodeId, frame: Vec<u8>) {
+        let mut peers = self.peers.lock().unwrap();
+        let queue = peers.entry(peer).or_insert_with(|| {
+            let (sender, receiver) = bounded(PEER_QUEUE_SIZE);
+            let writer = PeerWriter {
+                node_id: self.node_id,
+                peer,
+                addresses: self.addresses.clone(),
+                stopped: Arc::clone(&self.stopped),
+                connection: None,
+                backoff: RECONNECT_BACKOFF_MIN,
+                retry_at: Instant::now(),
+            };
+            thread::spawn(move || writer.run(receiver));
+            sender
+        });
+        if let Err(TrySendError::Full(_)) = queue.try_send(frame) {
+            error!("{} dropped a message to {}, queue full", self.node_id, peer);
+        }
+    }
+
+    // Forward the frames of one incoming connection from `source` until it
+    // closes
+    fn read_frames(&self, mut stream: TcpStream, source: SocketAddr, incoming: Sender<Message>) {
+        if let Err(error) = stream.set_read_timeout(Some(POLL_INTERVAL)) {
+            error!("{} failed to set up connection: {}", self.node_id, error);
+            return;
+        }
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 4096];
+        while !self.stopped.load(Ordering::SeqCst) {
+            match stream.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(amt) => buffer.extend_from_slice(&chunk[..amt]),
+                Err(ref error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(_) => return,
+            }
+            loop {
+                let payload = match next_frame(&mut buffer) {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => break,
+                    Err(error) => {
+                        error!("{} dropped connection from {}: {}", self.node_id, source, error);
+                        return;
+                    }
+                };
+                match Codec::decode(&payload) {
+                    Ok(msg_parsed) => {
+                        match reachable_addr(msg_parsed.from_addr, source.ip()) {
+                            Some(addr) => self.addresses.set(msg_parsed.from, addr),
+                            None => warn!(
+                                "{} ignored address {} of {} connected from {}",
+                                self.node_id, msg_parsed.from_addr, msg_parsed.from, source
+                            ),
+                        }
+                        if incoming.send(msg_parsed.message).is_err() {
+                            return;
+                        }
+                    }
+                    Err(error) => error!("{} dropped malformed message: {}", self.node_id, error),
+                }
+            }
+        }
+    }
+}
+
+// Where a peer listening on `reported`, as it says in its messages, can be
+// reached when it connects from `source`. Connections come from ephemeral
+// ports, so only the IP of the connection is known for sure: it replaces an
+// unspecified IP, and a loopback IP from another host is refused.
+fn reachable_addr(reported: SocketAddr, source: IpAddr) -> Option<SocketAddr> {
+    let ip = reported.ip();
+    if ip.is_unspecified() {
+        Some(SocketAddr::new(source, reported.port()))
+    } else if ip.is_loopback() && !source.is_loopback() {
+        None
+    } else {
+        Some(reported)
+    }
+}
+
+// Remove the first complete frame from `buffer` and return its payload.
+// Fails if the frame is larger than MAX_FRAME_SIZE.
+fn next_frame(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, CodecError> {
+    let header = match buffer.get(..FRAME_HEADER_SIZE) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(CodecError::TooLarge(length));
+    }
+    if buffer.len() < FRAME_HEADER_SIZE + length {
+        return Ok(None);
+    }
+    let payload = buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length].to_vec();
+    buffer.drain(..FRAME_HEADER_SIZE + length);
+    Ok(Some(payload))
+}
+
+// Owner of the outgoing connection to one peer
+struct PeerWriter {
+    node_id: NodeId,
+    peer: NodeId,
+    addresses: AddressBook,
+    stopped: Arc<AtomicBool>,
+    connection: Option<(SocketAddr, TcpStream)>,
+    backoff: Duration,
+    retry_at: Instant, // no connection attempt before then
+}
+
+impl PeerWriter {
+    fn run(mut self, frames: Receiver<Vec<u8>>) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            match frames.recv_timeout(POLL_INTERVAL) {
+                Ok(frame) => self.write(&frame),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    // Frames that cannot be written are dropped, Raft resends what matters
+    fn write(&mut self, frame: &[u8]) {
+        let stream = match self.connect() {
+            Some(stream) => stream,
+            None => return,
+        };
+        if let Err(error) = stream.write_all(frame) {
+            error!("{} lost connection to {}: {}", self.node_id, self.peer, error);
+            self.connection = None;
+            self.retry_at = Instant::now() + self.backoff;
+        }
+    }
+
+    // The connection to the peer's current address, if there is one or a
+    // new one can be opened
+    fn connect(&mut self) -> Option<&mut TcpStream> {
+        let addr = self.addresses.get(self.peer)?;
+        if self.connection.as_ref().is_some_and(|(connected, _)| *connected != addr) {
+            // the peer moved
+            self.connection = None;
+        }
+        if self.connection.is_none() {
+            if Instant::now() < self.retry_at {
+                return None;
+            }
+            match open(addr) {
+                Ok(stream) => {
+                    info!("{} connected to {} at {}", self.node_id, self.peer, addr);
+                    self.connection = Some((addr, stream));
+                    self.backoff = RECONNECT_BACKOFF_MIN;
+                }
+                Err(error) => {
+                    error!("{} failed to connect to {}: {}", self.node_id, self.peer, error);
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                    return None;
+                }
+            }
+        }
+        self.connection.as_mut().map(|(_, stream)| stream)
+    }
+}
+
+fn open(addr: SocketAddr) -> Result<TcpStream, Box<dyn Error>> {
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+impl Transport for TcpTransport {
+    fn send_to(&self, peer: NodeId, message: Message) -> Result<(), Box<dyn Error>> {
+        self.enqueue(peer, self.encode(message)?);
+        Ok(())
+    }
+
+    // The message is encoded once for all peers
+    fn broadcast(&self, peers: &[NodeId], message: Message) -> Result<(), Box<dyn Error>> {
+        let frame = self.encode(message)?;
+        for peer in peers {
+            self.enqueue(*peer, frame.clone());
+        }
+        Ok(())
+    }
+
+    // Accept connections until stopped, each one is read on its own thread
+    fn listen(&self, incoming: Sender<Message>) -> Result<(), Box<dyn Error>> {
+        thread::scope(|scope| {
+            while !self.stopped.load(Ordering::SeqCst) {
+                match self.listener.accept() {
+                    Ok((stream, source)) => {
+                        let incoming = incoming.clone();
+                        if let Err(error) = stream.set_nonblocking(false) {
+                            error!("{} failed to accept connection: {}", self.node_id, error);
+                            continue;
+                        }
+                        scope.spawn(move || self.read_frames(stream, source, incoming));
+                    }
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(POLL_INTERVAL)
+                    }
+                    Err(error) => error!("{} accept error: {}", self.node_id, error),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        Some(MAX_FRAME_SIZE)
+    }
+
+    fn set_address(&self, peer: NodeId, addr: SocketAddr) {
+        self.addresses.set(peer, addr);
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.socket_addr)
+    }
+}
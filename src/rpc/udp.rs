@@ -0,0 +1,125 @@
+use super::codec::Codec;
+use super::{Message, RPCMessage, Transport};
+use crate::address_book::{AddressBook, NodeId};
+
+use crossbeam_channel::Sender;
+use log::error;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// How often a blocked listener checks whether it was stopped
+const LISTENER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Largest UDP payload, bigger messages need TcpTransport
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// One encoded RPCMessage per datagram
+pub struct UdpTransport {
+    socket: UdpSocket,
+    node_id: NodeId,
+    socket_addr: SocketAddr,
+    addresses: AddressBook,
+    codec: Codec,
+    stopped: AtomicBool,
+}
+
+impl UdpTransport {
+    pub fn bind(
+        node_id: NodeId,
+        socket_addr: SocketAddr,
+        addresses: AddressBook,
+        codec: Codec,
+    ) -> Result<UdpTransport, Box<dyn Error>> {
+        let socket = UdpSocket::bind(socket_addr)?;
+        socket.set_read_timeout(Some(LISTENER_POLL_INTERVAL))?;
+        Ok(UdpTransport {
+            socket,
+            node_id,
+            socket_addr,
+            addresses,
+            codec,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    fn encode(&self, message: Message) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.codec.encode(&RPCMessage::new(self.node_id, self.socket_addr, message))
+    }
+}
+
+impl Transport for UdpTransport {
+    // Dropped like a lost datagram if the peer's address is unknown
+    fn send_to(&self, peer: NodeId, message: Message) -> Result<(), Box<dyn Error>> {
+        let peer_addr = match self.addresses.get(peer) {
+            Some(addr) => addr,
+            None => {
+                error!("{} has no address for node {}", self.node_id, peer);
+                return Ok(());
+            }
+        };
+        self.socket.send_to(&self.encode(message)?, peer_addr)?;
+        Ok(())
+    }
+
+    // The message is encoded once for all peers, a peer it cannot be sent
+    // to is skipped like in send_to
+    fn broadcast(&self, peers: &[NodeId], message: Message) -> Result<(), Box<dyn Error>> {
+        let buffer = self.encode(message)?;
+        for peer in peers {
+            if let Some(peer_addr) = self.addresses.get(*peer) {
+                if let Err(error) = self.socket.send_to(&buffer, peer_addr) {
+                    error!("{} failed to send to {}: {}", self.node_id, peer, error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn listen(&self, incoming: Sender<Message>) -> Result<(), Box<dyn Error>> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        while !self.stopped.load(Ordering::SeqCst) {
+            let (amt, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(pair) => pair,
+                Err(ref error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(error) => {
+                    error!("{} receive error: {}", self.node_id, error);
+                    continue;
+                }
+            };
+            // Handle the raw RPC request from socket buffer
+            match Codec::decode(&buffer[..amt]) {
+                Ok(msg_parsed) => {
+                    // datagrams are sent from the socket the peer listens on,
+                    // unlike the address it is bound to this one is reachable
+                    self.addresses.set(msg_parsed.from, source);
+                    incoming.send(msg_parsed.message)?
+                }
+                Err(error) => error!("{} dropped malformed message: {}", self.node_id, error),
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        Some(MAX_DATAGRAM_SIZE)
+    }
+
+    fn set_address(&self, peer: NodeId, addr: SocketAddr) {
+        self.addresses.set(peer, addr);
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.socket_addr)
+    }
+}
@@ -0,0 +1,17 @@
+use crate::entry::Entry;
+
+use std::error::Error;
+
+// The replicated application. A Node applies every committed entry to it in
+// log order, exactly once, and asks it for snapshots when compacting the log.
+pub trait StateMachine {
+    type Output;
+
+    fn apply(&mut self, entry: &Entry) -> Self::Output;
+
+    // Serialized state including every entry applied so far
+    fn snapshot(&self) -> Vec<u8>;
+
+    // Replace the whole state with the one serialized in `snapshot`
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), Box<dyn Error>>;
+}
@@ -0,0 +1,179 @@
+use super::hard_state::HardStateFile;
+use super::wal::{self, Wal};
+use super::{
+    decode_record, encode_record, write_atomically, HardState, MemStorage, Snapshot, SnapshotMeta,
+    Storage,
+};
+use crate::entry::Entry;
+use crate::error::StorageError;
+
+use log::warn;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const WAL_DIR: &str = "wal";
+
+// Storage persisting everything under one directory:
+//   hard_state.json  term, vote and commit index
+//   snapshot         `meta len | crc32 | 1 | bincode(meta) | data`
+//   wal/             log segments
+// All reads are served by an in-memory copy, writes hit the disk first.
+pub struct FileStorage {
+    dir: PathBuf,
+    wal: Wal,
+    hard_state_file: HardStateFile,
+    cache: MemStorage,
+}
+
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileStorage, Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        let (hard_state_file, hard_state) = HardStateFile::open(&dir)?;
+        let snapshot = read_snapshot(&dir)?;
+        let (mut wal, mut entries) = Wal::open(dir.join(WAL_DIR), wal::DEFAULT_SEGMENT_SIZE)?;
+
+        let mut cache = MemStorage::new();
+        cache.set_hard_state(&hard_state)?;
+        let meta = snapshot.meta.clone();
+        cache.apply_snapshot(snapshot)?;
+
+        // A crash while installing a snapshot can leave a log that does not
+        // continue it, such a log is stale and dropped
+        let continues_snapshot = match entries.first() {
+            Some(first) if first.index > meta.last_included_index + 1 => false,
+            Some(first) if first.index <= meta.last_included_index => entries
+                .iter()
+                .find(|entry| entry.index == meta.last_included_index)
+                .is_some_and(|entry| entry.term == meta.last_included_term),
+            _ => true,
+        };
+        if continues_snapshot {
+            entries.retain(|entry| entry.index > meta.last_included_index);
+            cache.append(&entries)?;
+        } else {
+            warn!(
+                "Discarding log in {} not continuing snapshot at {}",
+                dir.display(),
+                meta.last_included_index
+            );
+            wal.truncate_suffix(0)?;
+        }
+
+        Ok(FileStorage {
+            dir,
+            wal,
+            hard_state_file,
+            cache,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn hard_state(&self) -> HardState {
+        self.cache.hard_state()
+    }
+
+    fn set_hard_state(&mut self, state: &HardState) -> Result<(), Box<dyn Error>> {
+        self.hard_state_file.save(state)?;
+        self.cache.set_hard_state(state)
+    }
+
+    fn entries(&self, low: usize, high: usize) -> Result<Vec<Entry>, Box<dyn Error>> {
+        self.cache.entries(low, high)
+    }
+
+    fn term(&self, index: usize) -> Option<u32> {
+        self.cache.term(index)
+    }
+
+    fn first_index(&self) -> usize {
+        self.cache.first_index()
+    }
+
+    fn last_index(&self) -> usize {
+        self.cache.last_index()
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<(), Box<dyn Error>> {
+        if let Some(first) = entries.first() {
+            if first.index != self.last_index() + 1 {
+                return Err(Box::new(StorageError::NonContiguous {
+                    expected: self.last_index() + 1,
+                    found: first.index,
+                }));
+            }
+        }
+        self.wal.append(entries)?;
+        self.cache.append(entries)
+    }
+
+    fn truncate_suffix(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        if index < self.first_index() {
+            return Err(Box::new(StorageError::Compacted(index)));
+        }
+        self.wal.truncate_suffix(index)?;
+        self.cache.truncate_suffix(index)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.cache.snapshot()
+    }
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<(), Box<dyn Error>> {
+        let meta = snapshot.meta.clone();
+        if meta.last_included_index < self.cache.snapshot().meta.last_included_index {
+            return Ok(());
+        }
+        let keep_log = self.term(meta.last_included_index) == Some(meta.last_included_term);
+
+        // the snapshot goes to disk before the entries it replaces are removed
+        write_snapshot(&self.dir, &snapshot)?;
+        if keep_log {
+            self.wal.compact(meta.last_included_index)?;
+        } else {
+            self.wal.truncate_suffix(0)?;
+        }
+        self.cache.apply_snapshot(snapshot)
+    }
+}
+
+fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+    let meta = encode_record(&snapshot.meta)?;
+    let mut body = meta.clone();
+    body.extend_from_slice(&snapshot.data);
+
+    let mut content = Vec::with_capacity(8 + body.len());
+    content.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    content.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    content.extend_from_slice(&body);
+    write_atomically(dir, SNAPSHOT_FILE, &content)
+}
+
+fn read_snapshot(dir: &Path) -> Result<Snapshot, Box<dyn Error>> {
+    let path = dir.join(SNAPSHOT_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Snapshot::default()),
+        Err(error) => return Err(Box::new(error)),
+    };
+    if content.len() < 8 {
+        return Err(Box::new(StorageError::Corrupted(path)));
+    }
+    let mut meta_len = [0u8; 4];
+    let mut crc = [0u8; 4];
+    meta_len.copy_from_slice(&content[0..4]);
+    crc.copy_from_slice(&content[4..8]);
+    let meta_len = u32::from_le_bytes(meta_len) as usize;
+    let body = &content[8..];
+    if meta_len > body.len() || crc32fast::hash(body) != u32::from_le_bytes(crc) {
+        return Err(Box::new(StorageError::Corrupted(path)));
+    }
+    let meta: SnapshotMeta = decode_record(&body[..meta_len])?;
+    Ok(Snapshot {
+        meta,
+        data: body[meta_len..].to_vec(),
+    })
+}
@@ -0,0 +1,54 @@
+use super::write_atomically;
+use crate::address_book::NodeId;
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const HARD_STATE_FILE: &str = "hard_state.json";
+
+// The part of a node's state that must hit the disk before it answers any RPC
+#[derive(PartialEq, Clone, Default, Deserialize, Serialize, Debug)]
+pub struct HardState {
+    pub term: u32,
+    pub voted_for: Option<NodeId>,
+    pub commit_index: usize,
+}
+
+// Single-file store of the HardState, replaced atomically on every save so a
+// crash leaves either the previous or the new state on disk
+pub struct HardStateFile {
+    dir: PathBuf,
+    saved: HardState,
+}
+
+impl HardStateFile {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<(HardStateFile, HardState), Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let saved = match fs::read(dir.join(HARD_STATE_FILE)) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => HardState::default(),
+            Err(error) => return Err(Box::new(error)),
+        };
+        Ok((
+            HardStateFile {
+                dir,
+                saved: saved.clone(),
+            },
+            saved,
+        ))
+    }
+
+    // Durably replace the stored state, skipping the write if nothing changed
+    pub fn save(&mut self, state: &HardState) -> Result<(), Box<dyn Error>> {
+        if *state == self.saved {
+            return Ok(());
+        }
+        write_atomically(&self.dir, HARD_STATE_FILE, &serde_json::to_vec(state)?)?;
+        self.saved = state.clone();
+        Ok(())
+    }
+}
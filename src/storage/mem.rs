@@ -0,0 +1,111 @@
+use super::{HardState, Snapshot, Storage};
+use crate::entry::Entry;
+use crate::error::StorageError;
+
+use std::error::Error;
+
+// Storage kept entirely in memory, for tests or nodes that can afford to
+// lose their state on restart
+#[derive(Default)]
+pub struct MemStorage {
+    hard_state: HardState,
+    snapshot: Snapshot,
+    entries: Vec<Entry>, // entries[0].index == snapshot.meta.last_included_index + 1
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    fn offset(&self) -> usize {
+        self.snapshot.meta.last_included_index + 1
+    }
+}
+
+impl Storage for MemStorage {
+    fn hard_state(&self) -> HardState {
+        self.hard_state.clone()
+    }
+
+    fn set_hard_state(&mut self, state: &HardState) -> Result<(), Box<dyn Error>> {
+        self.hard_state = state.clone();
+        Ok(())
+    }
+
+    fn entries(&self, low: usize, high: usize) -> Result<Vec<Entry>, Box<dyn Error>> {
+        if low < self.first_index() {
+            return Err(Box::new(StorageError::Compacted(low)));
+        }
+        if high > self.last_index() + 1 {
+            return Err(Box::new(StorageError::Unavailable(high - 1)));
+        }
+        if low >= high {
+            return Ok(Vec::new());
+        }
+        let offset = self.offset();
+        Ok(self.entries[low - offset..high - offset].to_vec())
+    }
+
+    fn term(&self, index: usize) -> Option<u32> {
+        let meta = &self.snapshot.meta;
+        if index == meta.last_included_index {
+            return Some(meta.last_included_term);
+        }
+        if index < self.first_index() {
+            return None;
+        }
+        self.entries
+            .get(index - self.offset())
+            .map(|entry| entry.term)
+    }
+
+    fn first_index(&self) -> usize {
+        self.offset()
+    }
+
+    fn last_index(&self) -> usize {
+        self.snapshot.meta.last_included_index + self.entries.len()
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<(), Box<dyn Error>> {
+        for (entry, expected) in entries.iter().zip(self.last_index() + 1..) {
+            if entry.index != expected {
+                return Err(Box::new(StorageError::NonContiguous {
+                    expected,
+                    found: entry.index,
+                }));
+            }
+        }
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn truncate_suffix(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        if index < self.first_index() {
+            return Err(Box::new(StorageError::Compacted(index)));
+        }
+        let keep = index - self.offset();
+        self.entries.truncate(keep);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.snapshot.clone()
+    }
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<(), Box<dyn Error>> {
+        let meta = snapshot.meta.clone();
+        if meta.last_included_index < self.snapshot.meta.last_included_index {
+            return Ok(());
+        }
+        if self.term(meta.last_included_index) == Some(meta.last_included_term) {
+            let drop = meta.last_included_index + 1 - self.offset();
+            self.entries.drain(..drop.min(self.entries.len()));
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = snapshot;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+pub mod file;
+pub mod hard_state;
+pub mod mem;
+pub mod wal;
+
+pub use file::FileStorage;
+pub use hard_state::HardState;
+pub use mem::MemStorage;
+
+use crate::entry::Entry;
+use crate::error::CodecError;
+use crate::membership::Membership;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+// Metadata of a snapshot: the last log entry it replaces and the cluster
+// membership as of that entry
+#[derive(PartialEq, Clone, Default, Deserialize, Serialize, Debug)]
+pub struct SnapshotMeta {
+    pub last_included_index: usize,
+    pub last_included_term: u32,
+    // empty if no membership change was ever logged
+    #[serde(default)]
+    pub membership: Membership,
+}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct Snapshot {
+    pub meta: SnapshotMeta,
+    pub data: Vec<u8>,
+}
+
+// Everything a Node needs to keep durable. The log always starts right after
+// the snapshot: first_index() == snapshot.last_included_index + 1, and the
+// term at last_included_index stays known so consistency checks still work
+// after the prefix is gone. A fresh storage has an empty snapshot at index 0.
+pub trait Storage {
+    fn hard_state(&self) -> HardState;
+
+    fn set_hard_state(&mut self, state: &HardState) -> Result<(), Box<dyn Error>>;
+
+    // Entries in [low, high)
+    fn entries(&self, low: usize, high: usize) -> Result<Vec<Entry>, Box<dyn Error>>;
+
+    // Term of the entry at `index`, None if it was compacted or does not exist
+    fn term(&self, index: usize) -> Option<u32>;
+
+    fn first_index(&self) -> usize;
+
+    fn last_index(&self) -> usize;
+
+    // Append entries right after last_index()
+    fn append(&mut self, entries: &[Entry]) -> Result<(), Box<dyn Error>>;
+
+    // Remove every entry whose index is >= `index`
+    fn truncate_suffix(&mut self, index: usize) -> Result<(), Box<dyn Error>>;
+
+    fn snapshot(&self) -> Snapshot;
+
+    // Store `snapshot` and drop the log it covers. Entries following it are
+    // kept if the log contains its last included entry, otherwise the whole
+    // log is discarded.
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<(), Box<dyn Error>>;
+
+    fn last_term(&self) -> u32 {
+        self.term(self.last_index()).unwrap_or(0)
+    }
+}
+
+// First byte of a binary record. JSON records start with '{' and are still
+// read if they match the current types, e.g. entries with a kind and a byte
+// command.
+const BINARY_V1: u8 = 1;
+
+// Encode a log entry or snapshot metadata as stored on disk, or the
+// membership carried by a config change entry
+pub(crate) fn encode_record<T: Serialize>(value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = vec![BINARY_V1];
+    bincode::DefaultOptions::new().serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+pub(crate) fn decode_record<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Box<dyn Error>> {
+    match bytes.first() {
+        Some(&BINARY_V1) => Ok(bincode::DefaultOptions::new()
+            .with_limit(bytes.len() as u64)
+            .deserialize(&bytes[1..])?),
+        Some(b'{') => Ok(serde_json::from_slice(bytes)?),
+        Some(format) => Err(Box::new(CodecError::UnknownFormat(*format))),
+        None => Err(Box::new(CodecError::Empty)),
+    }
+}
+
+// Replace `dir/name` with `content`: write a temporary file, fsync it and
+// rename it over the old one
+fn write_atomically(dir: &Path, name: &str, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let mut tmp = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    tmp.write_all(content)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use super::{decode_record, encode_record};
+use crate::entry::Entry;
+use crate::error::StorageError;
+
+use log::warn;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Size of a record header: payload length (u32) + payload crc32 (u32)
+const RECORD_HEADER_SIZE: u64 = 8;
+const SEGMENT_SUFFIX: &str = "log";
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+// One append-only file holding the entries [first_index, first_index + offsets.len())
+struct Segment {
+    first_index: usize,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    offsets: Vec<u64>, // offsets[i] is the position of entry first_index + i
+}
+
+impl Segment {
+    fn path_for(dir: &Path, first_index: usize) -> PathBuf {
+        dir.join(format!("{:020}.{}", first_index, SEGMENT_SUFFIX))
+    }
+
+    fn create(dir: &Path, first_index: usize) -> Result<Segment, Box<dyn Error>> {
+        let path = Segment::path_for(dir, first_index);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        sync_dir(dir)?;
+        Ok(Segment {
+            first_index,
+            path,
+            file,
+            size: 0,
+            offsets: Vec::new(),
+        })
+    }
+
+    // Read every valid record of the segment. A torn record at the end of the
+    // last segment is cut off, anywhere else it means the log is corrupted.
+    // So does a record that is intact but does not hold the next entry.
+    fn load(
+        path: PathBuf,
+        first_index: usize,
+        is_last: bool,
+    ) -> Result<(Segment, Vec<Entry>), Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut pos = 0usize;
+        while pos < content.len() {
+            match read_record(&content[pos..]) {
+                Some((payload, len)) => {
+                    let entry = match decode_record::<Entry>(payload) {
+                        Ok(entry) if entry.index == first_index + entries.len() => entry,
+                        _ => return Err(Box::new(StorageError::Corrupted(path))),
+                    };
+                    offsets.push(pos as u64);
+                    entries.push(entry);
+                    pos += len;
+                }
+                None => {
+                    if !is_last {
+                        return Err(Box::new(StorageError::Corrupted(path)));
+                    }
+                    warn!(
+                        "Truncating torn tail of {} at offset {}",
+                        path.display(),
+                        pos
+                    );
+                    file.set_len(pos as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+            }
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((
+            Segment {
+                first_index,
+                path,
+                file,
+                size: pos as u64,
+                offsets,
+            },
+            entries,
+        ))
+    }
+
+    fn last_index(&self) -> usize {
+        self.first_index + self.offsets.len() - 1
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+}
+
+// Durable, append-only store of log entries split into segment files.
+// Every record is `len | crc32 | 1 | bincode(entry)`, and appends are fsynced
+// before returning so an acknowledged entry survives a crash.
+pub struct Wal {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    max_segment_size: u64,
+}
+
+impl Wal {
+    // Open the log stored in `dir`, creating it if needed, and return every
+    // entry found on disk in index order.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        max_segment_size: u64,
+    ) -> Result<(Wal, Vec<Entry>), Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut first_indexes = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_SUFFIX) {
+                continue;
+            }
+            if let Some(first_index) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<usize>().ok())
+            {
+                first_indexes.push(first_index);
+            }
+        }
+        first_indexes.sort_unstable();
+
+        let mut segments = Vec::new();
+        let mut entries: Vec<Entry> = Vec::new();
+        for (i, first_index) in first_indexes.iter().enumerate() {
+            let is_last = i + 1 == first_indexes.len();
+            if let Some(last) = entries.last() {
+                if last.index + 1 != *first_index {
+                    return Err(Box::new(StorageError::Corrupted(Segment::path_for(
+                        &dir,
+                        *first_index,
+                    ))));
+                }
+            }
+            let (segment, mut segment_entries) =
+                Segment::load(Segment::path_for(&dir, *first_index), *first_index, is_last)?;
+            if segment.is_empty() && !is_last {
+                return Err(Box::new(StorageError::Corrupted(segment.path)));
+            }
+            entries.append(&mut segment_entries);
+            segments.push(segment);
+        }
+
+        Ok((
+            Wal {
+                dir,
+                segments,
+                max_segment_size,
+            },
+            entries,
+        ))
+    }
+
+    pub fn last_index(&self) -> Option<usize> {
+        self.segments
+            .iter()
+            .rev()
+            .find(|segment| !segment.is_empty())
+            .map(|segment| segment.last_index())
+    }
+
+    // Append entries right after the last one and fsync them
+    pub fn append(&mut self, entries: &[Entry]) -> Result<(), Box<dyn Error>> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let first_expected = match self.last_index() {
+            Some(last_index) => last_index + 1,
+            None => entries[0].index,
+        };
+
+        // records are buffered so each segment gets a single write
+        let mut buffer = Vec::new();
+        for (entry, expected) in entries.iter().zip(first_expected..) {
+            if entry.index != expected {
+                return Err(Box::new(StorageError::NonContiguous {
+                    expected,
+                    found: entry.index,
+                }));
+            }
+            let need_new_segment = match self.segments.last() {
+                Some(segment) => {
+                    segment.size >= self.max_segment_size
+                        || (segment.is_empty() && segment.first_index != entry.index)
+                }
+                None => true,
+            };
+            if need_new_segment {
+                if !buffer.is_empty() {
+                    let segment = self.segments.last_mut().unwrap();
+                    segment.file.write_all(&buffer)?;
+                    segment.file.sync_data()?;
+                    buffer.clear();
+                }
+                self.drop_empty_tail()?;
+                self.segments.push(Segment::create(&self.dir, entry.index)?);
+            }
+
+            let record = write_record(entry)?;
+            let segment = self.segments.last_mut().unwrap();
+            segment.offsets.push(segment.size);
+            segment.size += record.len() as u64;
+            buffer.extend_from_slice(&record);
+        }
+        let segment = self.segments.last_mut().unwrap();
+        segment.file.write_all(&buffer)?;
+        segment.file.sync_data()?;
+        Ok(())
+    }
+
+    // Remove every entry whose index is >= `index`, used when a leader
+    // overwrites a conflicting suffix
+    pub fn truncate_suffix(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.first_index >= index {
+                fs::remove_file(&segment.path)?;
+                self.segments.pop();
+                continue;
+            }
+            let keep = index - segment.first_index;
+            if keep < segment.offsets.len() {
+                let new_size = segment.offsets[keep];
+                segment.file.set_len(new_size)?;
+                segment.file.seek(SeekFrom::Start(new_size))?;
+                segment.file.sync_all()?;
+                segment.offsets.truncate(keep);
+                segment.size = new_size;
+            }
+            break;
+        }
+        sync_dir(&self.dir)
+    }
+
+    // Delete the segments holding only entries <= `index`. The segment that
+    // contains `index` is kept whole, callers skip its compacted entries.
+    pub fn compact(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let removable = self
+            .segments
+            .iter()
+            .take_while(|segment| !segment.is_empty() && segment.last_index() <= index)
+            .count();
+        for segment in self.segments.drain(..removable) {
+            fs::remove_file(&segment.path)?;
+        }
+        sync_dir(&self.dir)
+    }
+
+    fn drop_empty_tail(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(segment) = self.segments.last() {
+            if segment.is_empty() {
+                fs::remove_file(&segment.path)?;
+                self.segments.pop();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_record(entry: &Entry) -> Result<Vec<u8>, Box<dyn Error>> {
+    let payload = encode_record(entry)?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+// Returns the payload and the whole record length, or None if the record is
+// incomplete or does not match its checksum, as left by a crash mid-write
+fn read_record(buffer: &[u8]) -> Option<(&[u8], usize)> {
+    let header = RECORD_HEADER_SIZE as usize;
+    if buffer.len() < header {
+        return None;
+    }
+    let mut len = [0u8; 4];
+    let mut crc = [0u8; 4];
+    len.copy_from_slice(&buffer[0..4]);
+    crc.copy_from_slice(&buffer[4..8]);
+    let len = u32::from_le_bytes(len) as usize;
+    let payload = buffer.get(header..header + len)?;
+    if crc32fast::hash(payload) != u32::from_le_bytes(crc) {
+        return None;
+    }
+    Some((payload, header + len))
+}
+
+// Make file creation and removal in `dir` durable
+fn sync_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -1,88 +1,107 @@
-use crossbeam_channel::{bounded, select, Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
 use rand::Rng;
 use std::error::Error;
-use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+// Bounds of the randomized election timeout, in milliseconds
+pub const ELECTION_TIMEOUT_MIN: u64 = 150;
+pub const ELECTION_TIMEOUT_MAX: u64 = 300;
 
+// Every run_* call starts a new generation of the timer. Threads of an older
+// generation exit without firing, and the generation sent with each tick lets
+// the receiver drop ticks queued before the timer was restarted. Clones
+// share the same timer.
 #[derive(Clone)]
-struct Clock {
-    canceller: Sender<()>,
-    cancellee: Receiver<()>,
-}
-
-impl Clock {
-    fn sleep(&self, duration: Duration) -> io::Result<()> {
-        select! {
-            recv(self.cancellee) -> _ => Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                "Timer cancelled"),
-            ),
-            default(duration) => Ok(())
-        }
-    }
-}
-
 pub struct NodeTimer {
-    notifier: Arc<Sender<()>>,
-    pub receiver: Arc<Receiver<()>>,
-    clock: Arc<Clock>,
+    notifier: Arc<Sender<usize>>,
+    pub receiver: Arc<Receiver<usize>>,
+    generation: Arc<AtomicUsize>,
+    election_deadline: Arc<Mutex<Instant>>,
     heartbeat_interval: Arc<Duration>,
+    stopped: Arc<AtomicBool>,
 }
 
 impl NodeTimer {
-    /// Create a [Timer](struct.Timer.html) and its associated [Canceller](struct.Canceller.html).
     pub fn new(interval: u32) -> Result<Self, Box<dyn Error>> {
-        let (notifier, receiver) = bounded(0);
-        let (canceller, cancellee) = bounded(0);
+        // one slot, so a tick is not lost while the node is busy
+        let (notifier, receiver) = bounded(1);
 
         Ok(NodeTimer {
             notifier: Arc::new(notifier),
             receiver: Arc::new(receiver),
-            clock: Arc::new(Clock {
-                canceller,
-                cancellee,
-            }),
+            generation: Arc::new(AtomicUsize::new(0)),
+            election_deadline: Arc::new(Mutex::new(Instant::now())),
             heartbeat_interval: Arc::new(Duration::from_millis(interval as u64)),
+            stopped: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    // Whether a tick received from `receiver` comes from the running timer
+    pub fn is_current(&self, generation: usize) -> bool {
+        generation == self.generation.load(Ordering::SeqCst)
+    }
+
+    fn next_generation(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Fire once after a random election timeout
     pub fn run_elect(&self) {
-        let clock = Arc::clone(&self.clock);
+        let generation = self.next_generation();
+        self.reset_elect();
+        let current = Arc::clone(&self.generation);
+        let deadline = Arc::clone(&self.election_deadline);
         let notifier = Arc::clone(&self.notifier);
-        thread::spawn(move || {
-            let mut interval = Duration::from_millis(rand::thread_rng().gen_range(150, 300));
-            while clock.sleep(interval).is_err() {
-                // timer.sleep return Ok(()) if the given time has elapsed
-                // else return Err(...)
-                interval = Duration::from_millis(rand::thread_rng().gen_range(150, 300));
+        let stopped = Arc::clone(&self.stopped);
+        thread::spawn(move || loop {
+            if stopped.load(Ordering::SeqCst) || current.load(Ordering::SeqCst) != generation {
+                return;
             }
-            notifier.try_send(());
+            let now = Instant::now();
+            let wake_at = *deadline.lock().unwrap();
+            if wake_at > now {
+                thread::sleep(wake_at - now);
+                continue;
+            }
+            let _ = notifier.try_send(generation);
+            return;
         });
     }
 
-    // must run timer before reset
+    // Push the election timeout back, e.g. on a message from the leader
     pub fn reset_elect(&self) {
-        self.clock.canceller.try_send(());
+        let interval = Duration::from_millis(
+            rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX),
+        );
+        *self.election_deadline.lock().unwrap() = Instant::now() + interval;
     }
 
     // start heartbeat
     pub fn run_heartbeat(&self) {
-        let clock = Arc::clone(&self.clock);
+        let generation = self.next_generation();
+        let current = Arc::clone(&self.generation);
         let notifier = Arc::clone(&self.notifier);
         let heartbeat_interval = Arc::clone(&self.heartbeat_interval);
+        let stopped = Arc::clone(&self.stopped);
 
-        thread::spawn(move || {
-            while clock.sleep(*heartbeat_interval).is_ok() {
-                notifier.try_send(());
+        thread::spawn(move || loop {
+            thread::sleep(*heartbeat_interval);
+            if stopped.load(Ordering::SeqCst) || current.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            if let Err(TrySendError::Disconnected(_)) = notifier.try_send(generation) {
+                // the node is gone
+                return;
             }
         });
     }
 
-    // can be deleted, depend on how to change leader to follower
-    pub fn stop_heartbeat(&self) {
-        self.clock.canceller.try_send(());
+    // Stop every timer thread, they exit within one interval
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.next_generation();
     }
 }